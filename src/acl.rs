@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cache::CacheOperation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+impl From<CacheOperation> for Permission {
+    fn from(operation: CacheOperation) -> Self {
+        match operation {
+            CacheOperation::Get => Self::Read,
+            CacheOperation::Set => Self::Write,
+            CacheOperation::Delete => Self::Delete,
+        }
+    }
+}
+
+// Grants `permissions` on the data names matching `pattern` to one client, or to every
+// client when `client` is unset. A pattern ending in `*` matches by prefix, so
+// `/satellite/*` covers everything under `/satellite/`; any other pattern matches exactly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclRule {
+    #[serde(default)]
+    pub client: Option<u16>,
+    pub pattern: String,
+    pub permissions: Vec<Permission>,
+}
+
+impl AclRule {
+    pub fn new(client: Option<u16>, pattern: &str, permissions: &[Permission]) -> Self {
+        Self {
+            client,
+            pattern: pattern.to_string(),
+            permissions: permissions.to_vec(),
+        }
+    }
+
+    pub fn allows(&self, client_id: u16, data_name: &str, permission: Permission) -> bool {
+        self.client.is_none_or(|client| client == client_id)
+            && self.permissions.contains(&permission)
+            && match self.pattern.strip_suffix('*') {
+                Some(prefix) => data_name.starts_with(prefix),
+                None => data_name == self.pattern,
+            }
+    }
+}
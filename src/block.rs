@@ -1,11 +1,80 @@
 use ring::digest::{digest, SHA256};
+use ring::signature::{self, UnparsedPublicKey};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::fmt;
 
-use crate::transaction::Transaction;
-use crate::utils::serialize_hash;
+use crate::clock::Clock;
+use crate::protocol::{read_array, read_u16, read_u64, ProtocolError};
+use crate::transaction::{Transaction, TRANSACTION_SIZE};
+use crate::utils::{deserialize_signature, hex_string, serialize_hash, serialize_signature};
 
 pub const BLOCK_PERIOD: u64 = 30; // seconds
+pub const BLOCK_HEADER_SIZE: usize = 138;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    PrevBlockHashMismatch,
+    MerkleRootMismatch,
+    TransactionHashMismatch([u8; 32]),
+    UnknownSigner(u16),
+    InvalidTransactionSignature([u8; 32]),
+    InvalidClientSignature([u8; 32]),
+    InvalidBlockSignature,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PrevBlockHashMismatch => write!(f, "previous block hash does not match tip"),
+            Self::MerkleRootMismatch => write!(f, "merkle root does not match transactions"),
+            Self::TransactionHashMismatch(hash) => {
+                write!(f, "transaction {} has an invalid hash", hex_string(hash))
+            }
+            Self::UnknownSigner(node_id) => write!(f, "no public key known for node {}", node_id),
+            Self::InvalidTransactionSignature(hash) => {
+                write!(f, "transaction {} has an invalid signature", hex_string(hash))
+            }
+            Self::InvalidClientSignature(hash) => {
+                write!(
+                    f,
+                    "transaction {} has an invalid client signature",
+                    hex_string(hash)
+                )
+            }
+            Self::InvalidBlockSignature => write!(f, "block has an invalid leader signature"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleSibling {
+    #[serde(serialize_with = "serialize_hash")]
+    pub hash: [u8; 32],
+    // Whether the sibling sits on the left of the running hash
+    pub left: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    #[serde(serialize_with = "serialize_hash")]
+    pub transaction_hash: [u8; 32],
+    pub siblings: Vec<MerkleSibling>,
+}
+
+impl MerkleProof {
+    pub fn verify(&self, merkle_root: &[u8; 32]) -> bool {
+        let mut hash = self.transaction_hash;
+        for sibling in &self.siblings {
+            hash = if sibling.left {
+                Block::hash_pair(&sibling.hash, &hash)
+            } else {
+                Block::hash_pair(&hash, &sibling.hash)
+            };
+        }
+        &hash == merkle_root
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -14,15 +83,24 @@ pub struct Block {
     #[serde(serialize_with = "serialize_hash")]
     pub prev_block_hash: [u8; 32],
     pub timestamp: u64,
+    pub leader: u16,
+    // Leader's signature over the block header
+    #[serde(
+        serialize_with = "serialize_signature",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: [u8; 64],
     pub transactions: Vec<Transaction>,
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_block_hash: [u8; 32]) -> Self {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    pub fn new(
+        clock: &dyn Clock,
+        transactions: Vec<Transaction>,
+        prev_block_hash: [u8; 32],
+        leader: u16,
+    ) -> Self {
+        let timestamp = clock.now_secs();
 
         let transaction_hashes: Vec<[u8; 32]> = transactions.iter().map(|txn| txn.hash).collect();
 
@@ -33,35 +111,98 @@ impl Block {
             merkle_root,
             prev_block_hash,
             timestamp,
+            leader,
+            signature: [0; 64],
             transactions,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let transactions = bytes[72..]
-            .chunks(109)
-            .map(|txn| Transaction::from_bytes(txn))
-            .collect();
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let signature = read_array(bytes, 74)?;
+        let transactions = bytes[BLOCK_HEADER_SIZE..]
+            .chunks(TRANSACTION_SIZE)
+            .map(Transaction::from_bytes)
+            .collect::<Result<_, _>>()?;
 
-        Self {
-            merkle_root: bytes[0..32].try_into().unwrap(),
-            prev_block_hash: bytes[32..64].try_into().unwrap(),
-            timestamp: u64::from_le_bytes(bytes[64..72].try_into().unwrap()),
+        Ok(Self {
+            merkle_root: read_array(bytes, 0)?,
+            prev_block_hash: read_array(bytes, 32)?,
+            timestamp: read_u64(bytes, 64)?,
+            leader: read_u16(bytes, 72)?,
+            signature,
             transactions,
-        }
+        })
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    // Header fields covered by the leader's signature
+    pub fn signing_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.merkle_root);
         bytes.extend_from_slice(&self.prev_block_hash);
-        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.leader.to_le_bytes());
+        bytes
+    }
+
+    pub fn verify_signature(&self, public_key: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&self.signing_bytes(), &self.signature)
+            .is_ok()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.signing_bytes();
+        bytes.extend_from_slice(&self.signature);
         for transaction in &self.transactions {
             bytes.extend_from_slice(&transaction.as_bytes());
         }
         bytes
     }
 
+    // Checks the leader's signature, linkage to `prev_block_hash`, the merkle root and
+    // every transaction's hash and signature against the originating node's public key,
+    // along with the signature of the client that requested it
+    pub fn validate(
+        &self,
+        prev_block_hash: &[u8; 32],
+        public_keys: &HashMap<u16, Vec<u8>>,
+    ) -> Result<(), ValidationError> {
+        let Some(leader_key) = public_keys.get(&self.leader) else {
+            return Err(ValidationError::UnknownSigner(self.leader));
+        };
+        if !self.verify_signature(leader_key) {
+            return Err(ValidationError::InvalidBlockSignature);
+        }
+
+        if &self.prev_block_hash != prev_block_hash {
+            return Err(ValidationError::PrevBlockHashMismatch);
+        }
+
+        let transaction_hashes: Vec<[u8; 32]> =
+            self.transactions.iter().map(|txn| txn.hash).collect();
+        if Self::calculate_merkle_root(&transaction_hashes, self.timestamp, &self.prev_block_hash)
+            != self.merkle_root
+        {
+            return Err(ValidationError::MerkleRootMismatch);
+        }
+
+        for txn in &self.transactions {
+            if txn.calculate_hash() != txn.hash {
+                return Err(ValidationError::TransactionHashMismatch(txn.hash));
+            }
+            let Some(public_key) = public_keys.get(&txn.node_id) else {
+                return Err(ValidationError::UnknownSigner(txn.node_id));
+            };
+            if !txn.verify_signature(public_key) {
+                return Err(ValidationError::InvalidTransactionSignature(txn.hash));
+            }
+            if !txn.verify_client_signature() {
+                return Err(ValidationError::InvalidClientSignature(txn.hash));
+            }
+        }
+        Ok(())
+    }
+
     fn calculate_merkle_root(
         hashes: &[[u8; 32]],
         timestamp: u64,
@@ -81,18 +222,53 @@ impl Block {
             return hashes[0];
         }
 
-        let mut next_level = Vec::new();
+        Self::calculate_merkle_root(&Self::next_level(hashes), timestamp, prev_block_hash)
+    }
 
-        for chunk in hashes.chunks(2) {
-            let mut combined = Vec::with_capacity(64);
-            combined.extend_from_slice(&chunk[0]);
-            combined.extend_from_slice(chunk.get(1).unwrap_or(&chunk[0]));
+    pub fn merkle_proof(&self, transaction_hash: &[u8; 32]) -> Option<MerkleProof> {
+        let mut level: Vec<[u8; 32]> = self.transactions.iter().map(|txn| txn.hash).collect();
+        let mut index = level.iter().position(|hash| hash == transaction_hash)?;
+        let mut siblings = Vec::new();
 
-            let mut hash = [0u8; 32];
-            hash.copy_from_slice(digest(&SHA256, &combined).as_ref());
-            next_level.push(hash);
+        while level.len() > 1 {
+            // An odd node at the end of a level is paired with itself
+            let (sibling, left) = if index % 2 == 0 {
+                (*level.get(index + 1).unwrap_or(&level[index]), false)
+            } else {
+                (level[index - 1], true)
+            };
+            siblings.push(MerkleSibling {
+                hash: sibling,
+                left,
+            });
+            level = Self::next_level(&level);
+            index /= 2;
         }
 
-        Self::calculate_merkle_root(&next_level, timestamp, prev_block_hash)
+        Some(MerkleProof {
+            transaction_hash: *transaction_hash,
+            siblings,
+        })
+    }
+
+    pub fn verify_merkle_proof(&self, proof: &MerkleProof) -> bool {
+        proof.verify(&self.merkle_root)
+    }
+
+    fn next_level(hashes: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        hashes
+            .chunks(2)
+            .map(|chunk| Self::hash_pair(&chunk[0], chunk.get(1).unwrap_or(&chunk[0])))
+            .collect()
+    }
+
+    fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut combined = Vec::with_capacity(64);
+        combined.extend_from_slice(left);
+        combined.extend_from_slice(right);
+
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(digest(&SHA256, &combined).as_ref());
+        hash
     }
 }
@@ -1,11 +1,27 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::block::Block;
+use crate::protocol::ProtocolError;
+use crate::utils::sha256;
+
+pub const COMPACTION_THRESHOLD: usize = 1024; // log records between snapshots
 
 pub trait Cache {
     fn get(&mut self, key: &str) -> Option<Vec<u8>>;
-    fn set(&mut self, key: &str, value: &[u8]);
-    fn delete(&mut self, key: &str);
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()>;
+    fn delete(&mut self, key: &str) -> io::Result<()>;
     fn metadata(&self) -> Vec<CachedDataMeta>;
+    fn entries(&self) -> Vec<(String, Vec<u8>)>;
+    fn value_hash(&self, key: &str) -> Option<[u8; 32]>;
+    // Brings entries in line with the operations recorded on the chain, using the
+    // values the cache still holds. Returns the names whose value is unavailable
+    fn rebuild(&mut self, chain: &[Block]) -> io::Result<Vec<String>>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,13 +40,15 @@ pub enum CacheOperation {
     Delete,
 }
 
-impl From<u8> for CacheOperation {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for CacheOperation {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            0 => Self::Set,
-            1 => Self::Get,
-            2 => Self::Delete,
-            _ => panic!("Invalid cache operation"),
+            0 => Ok(Self::Set),
+            1 => Ok(Self::Get),
+            2 => Ok(Self::Delete),
+            _ => Err(ProtocolError::UnknownOperation(value)),
         }
     }
 }
@@ -48,26 +66,24 @@ impl InMemoryCache {
     }
 }
 
-impl Cache for InMemoryCache {
-    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+impl Default for InMemoryCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryCache {
+    fn get_at(&mut self, key: &str, now: u64) -> Option<Vec<u8>> {
         if let Some((value, meta)) = self.map.get_mut(key) {
             meta.transactions += 1;
-            meta.last_accessed = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+            meta.last_accessed = now;
             Some(value.clone())
         } else {
             None
         }
     }
 
-    fn set(&mut self, key: &str, value: &[u8]) {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
+    fn set_at(&mut self, key: &str, value: &[u8], now: u64) {
         if let Some((existing_value, meta)) = self.map.get_mut(key) {
             // Update existing entry
             *existing_value = value.to_vec();
@@ -92,11 +108,419 @@ impl Cache for InMemoryCache {
         }
     }
 
-    fn delete(&mut self, key: &str) {
+    // Applies the latest operation the chain records for each name, unless the local
+    // entry was updated more recently. `stored_values` supplies values by hash for
+    // entries whose current value differs from the chain's
+    fn replay(
+        &mut self,
+        chain: &[Block],
+        stored_values: &HashMap<[u8; 32], Vec<u8>>,
+    ) -> Vec<String> {
+        let mut history: HashMap<&str, CachedDataMeta> = HashMap::new();
+        let mut latest: HashMap<&str, (CacheOperation, [u8; 32])> = HashMap::new();
+        for txn in chain.iter().flat_map(|block| &block.transactions) {
+            if txn.denied {
+                continue;
+            }
+            let meta = history
+                .entry(txn.data_name.as_str())
+                .or_insert_with(|| CachedDataMeta {
+                    name: txn.data_name.clone(),
+                    size: 0,
+                    last_updated: 0,
+                    last_accessed: 0,
+                    transactions: 0,
+                });
+            meta.transactions += 1;
+            match txn.operation {
+                CacheOperation::Get => meta.last_accessed = meta.last_accessed.max(txn.timestamp),
+                CacheOperation::Set | CacheOperation::Delete => {
+                    if txn.timestamp >= meta.last_updated {
+                        meta.last_updated = txn.timestamp;
+                        latest.insert(txn.data_name.as_str(), (txn.operation, txn.value_hash));
+                    }
+                }
+            }
+        }
+
+        let mut missing = Vec::new();
+        for (name, (operation, value_hash)) in latest {
+            let mut meta = history.remove(name).unwrap();
+            if let Some((value, local_meta)) = self.map.get(name) {
+                // Timestamps are in seconds, so on a tie trust the local log, which
+                // records operations in order
+                if local_meta.last_updated >= meta.last_updated {
+                    continue;
+                }
+                if sha256(value) == value_hash {
+                    meta.size = value.len();
+                    meta.transactions = meta.transactions.max(local_meta.transactions);
+                    meta.last_accessed = meta.last_accessed.max(local_meta.last_accessed);
+                    self.map.get_mut(name).unwrap().1 = meta;
+                    continue;
+                }
+            }
+            if operation == CacheOperation::Delete {
+                self.map.remove(name);
+                continue;
+            }
+            let Some(value) = stored_values.get(&value_hash) else {
+                missing.push(name.to_string());
+                continue;
+            };
+            meta.size = value.len();
+            self.map.insert(name.to_string(), (value.clone(), meta));
+        }
+        missing
+    }
+}
+
+impl Cache for InMemoryCache {
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.get_at(key, now())
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.set_at(key, value, now());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
         self.map.remove(key);
+        Ok(())
+    }
+
+    fn metadata(&self) -> Vec<CachedDataMeta> {
+        self.map.values().map(|value| value.1.clone()).collect()
+    }
+
+    fn entries(&self) -> Vec<(String, Vec<u8>)> {
+        self.map
+            .iter()
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn value_hash(&self, key: &str) -> Option<[u8; 32]> {
+        self.map.get(key).map(|(value, _)| sha256(value))
+    }
+
+    fn rebuild(&mut self, chain: &[Block]) -> io::Result<Vec<String>> {
+        Ok(self.replay(chain, &HashMap::new()))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum LogRecord {
+    Set {
+        name: String,
+        value: String,
+        at: u64,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry {
+    value: String,
+    meta: CachedDataMeta,
+}
+
+// Keeps entries in memory and makes them durable with a write-ahead log in `dir`,
+// compacted into a snapshot every `COMPACTION_THRESHOLD` records
+#[derive(Debug)]
+pub struct DurableCache {
+    memory: InMemoryCache,
+    dir: PathBuf,
+    log: File,
+    log_records: usize,
+    // Every value seen while loading, kept for `rebuild`
+    stored_values: HashMap<[u8; 32], Vec<u8>>,
+    discarded_records: usize,
+}
+
+impl DurableCache {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut memory = InMemoryCache::new();
+        let mut stored_values = HashMap::new();
+
+        let snapshot_path = dir.join("snapshot.json");
+        if snapshot_path.exists() {
+            let snapshot: Vec<SnapshotEntry> =
+                serde_json::from_slice(&fs::read(&snapshot_path)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for entry in snapshot {
+                let value = decode_value(&entry.value)?;
+                stored_values.insert(sha256(&value), value.clone());
+                memory
+                    .map
+                    .insert(entry.meta.name.clone(), (value, entry.meta));
+            }
+        }
+
+        let log_path = dir.join("cache.log");
+        let mut log_records = 0;
+        let mut valid_len = 0;
+        let mut discarded_records = 0;
+        if log_path.exists() {
+            let bytes = fs::read(&log_path)?;
+            let mut lines = bytes.split_inclusive(|byte| *byte == b'\n');
+            for line in lines.by_ref() {
+                // A torn final write leaves a line without its newline or one that
+                // does not parse
+                let Some(line) = line.strip_suffix(b"\n") else {
+                    discarded_records = 1;
+                    break;
+                };
+                let Ok(record) = serde_json::from_slice::<LogRecord>(line) else {
+                    discarded_records = 1;
+                    break;
+                };
+                match record {
+                    LogRecord::Set { name, value, at } => {
+                        let value = decode_value(&value)?;
+                        memory.set_at(&name, &value, at);
+                        stored_values.insert(sha256(&value), value);
+                    }
+                    LogRecord::Delete { name } => {
+                        memory.map.remove(&name);
+                    }
+                }
+                log_records += 1;
+                valid_len += line.len() as u64 + 1;
+            }
+            discarded_records += lines.count();
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        log.set_len(valid_len)?;
+
+        Ok(Self {
+            memory,
+            dir,
+            log,
+            log_records,
+            stored_values,
+            discarded_records,
+        })
+    }
+
+    // Log records dropped as torn or corrupt when the cache was opened
+    pub fn discarded_records(&self) -> usize {
+        self.discarded_records
+    }
+
+    // Writes every entry to a fresh snapshot and empties the log
+    pub fn compact(&mut self) -> io::Result<()> {
+        let snapshot: Vec<SnapshotEntry> = self
+            .memory
+            .map
+            .values()
+            .map(|(value, meta)| SnapshotEntry {
+                value: STANDARD.encode(value),
+                meta: meta.clone(),
+            })
+            .collect();
+        let tmp_path = self.dir.join("snapshot.json.tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec(&snapshot)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join("snapshot.json"))?;
+
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.log_records = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, record: LogRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        self.log.sync_data()?;
+        self.log_records += 1;
+        if self.log_records >= COMPACTION_THRESHOLD {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+impl Cache for DurableCache {
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        // Reads only update bookkeeping, which is kept in memory and saved with the
+        // next snapshot rather than logged
+        self.memory.get_at(key, now())
+    }
+
+    // The entry only changes once its log record is written
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        let at = now();
+        let record = LogRecord::Set {
+            name: key.to_string(),
+            value: STANDARD.encode(value),
+            at,
+        };
+        self.append(record)?;
+        self.memory.set_at(key, value, at);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        let record = LogRecord::Delete {
+            name: key.to_string(),
+        };
+        self.append(record)?;
+        self.memory.map.remove(key);
+        Ok(())
     }
 
     fn metadata(&self) -> Vec<CachedDataMeta> {
-        self.map.iter().map(|(_, value)| value.1.clone()).collect()
+        self.memory.metadata()
+    }
+
+    fn entries(&self) -> Vec<(String, Vec<u8>)> {
+        self.memory.entries()
+    }
+
+    fn value_hash(&self, key: &str) -> Option<[u8; 32]> {
+        self.memory.value_hash(key)
+    }
+
+    fn rebuild(&mut self, chain: &[Block]) -> io::Result<Vec<String>> {
+        let missing = self.memory.replay(chain, &self.stored_values);
+        self.stored_values.clear();
+        self.compact()?;
+        Ok(missing)
+    }
+}
+
+fn decode_value(value: &str) -> io::Result<Vec<u8>> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Empty cache directory unique to the test
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atlas-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn append_to_log(dir: &Path, bytes: &[u8]) {
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("cache.log"))
+            .unwrap()
+            .write_all(bytes)
+            .unwrap();
+    }
+
+    #[test]
+    fn logged_operations_are_replayed_on_open() {
+        let dir = cache_dir("replay");
+        let mut cache = DurableCache::open(&dir).unwrap();
+        cache.set("a", b"one").unwrap();
+        cache.set("b", b"two").unwrap();
+        cache.set("b", b"three").unwrap();
+        cache.delete("a").unwrap();
+        drop(cache);
+
+        let mut cache = DurableCache::open(&dir).unwrap();
+        assert_eq!(cache.discarded_records(), 0);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(b"three".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_final_record_is_cut_off() {
+        let dir = cache_dir("torn");
+        let mut cache = DurableCache::open(&dir).unwrap();
+        cache.set("a", b"one").unwrap();
+        drop(cache);
+        let intact_len = fs::metadata(dir.join("cache.log")).unwrap().len();
+        append_to_log(&dir, br#"{"op":"set","name":"b","val"#);
+
+        let mut cache = DurableCache::open(&dir).unwrap();
+        assert_eq!(cache.discarded_records(), 1);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(
+            fs::metadata(dir.join("cache.log")).unwrap().len(),
+            intact_len
+        );
+
+        // Later records follow the intact ones rather than the torn bytes
+        cache.set("c", b"two").unwrap();
+        drop(cache);
+        let mut cache = DurableCache::open(&dir).unwrap();
+        assert_eq!(cache.discarded_records(), 0);
+        assert_eq!(cache.get("a"), Some(b"one".to_vec()));
+        assert_eq!(cache.get("c"), Some(b"two".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_after_a_corrupt_one_are_discarded() {
+        let dir = cache_dir("corrupt");
+        let mut cache = DurableCache::open(&dir).unwrap();
+        cache.set("a", b"one").unwrap();
+        drop(cache);
+        append_to_log(&dir, b"not a record\n");
+        append_to_log(&dir, b"{\"op\":\"delete\",\"name\":\"a\"}\n");
+
+        let mut cache = DurableCache::open(&dir).unwrap();
+        assert_eq!(cache.discarded_records(), 2);
+        assert_eq!(cache.get("a"), Some(b"one".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_log_is_replayed_over_the_snapshot() {
+        let dir = cache_dir("snapshot");
+        let mut cache = DurableCache::open(&dir).unwrap();
+        cache.set("a", b"one").unwrap();
+        cache.set("b", b"two").unwrap();
+        cache.compact().unwrap();
+        assert_eq!(fs::metadata(dir.join("cache.log")).unwrap().len(), 0);
+        cache.set("a", b"three").unwrap();
+        cache.delete("b").unwrap();
+        drop(cache);
+
+        let mut cache = DurableCache::open(&dir).unwrap();
+        assert_eq!(cache.get("a"), Some(b"three".to_vec()));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.metadata().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_snapshot_that_does_not_parse_fails_the_open() {
+        let dir = cache_dir("bad-snapshot");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("snapshot.json"), b"[{").unwrap();
+
+        let e = DurableCache::open(&dir).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -1,120 +1,467 @@
+use crate::cache::CacheOperation;
+use crate::clock::{Clock, SystemClock};
+use crate::keys;
 use crate::protocol::{
-    AckPayload, DataPayload, Packet, PacketType, ACK_TIMEOUT, MAX_RETRIES, PACKET_BUFFER_SIZE,
+    initial_sequence, AckPayload, ConfirmPayload, DataPayload, ErrorCode, ErrorPayload,
+    GetProofPayload, HandshakePayload, Packet, PacketType, ProofPayload, ProtocolError,
+    Reassembler, RequestPayload, ACK_TIMEOUT, MAX_RETRIES, PACKET_BUFFER_SIZE, REASSEMBLY_TIMEOUT,
 };
-use std::{net::UdpSocket, thread, time::Duration};
+use crate::session::{Handshake, Session, SESSION_LIFETIME};
+use crate::transaction::ClientSignature;
+use crate::transport::{Transport, UdpTransport};
+use crate::utils::sha256;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::{collections::VecDeque, fmt, sync::Arc, time::Duration};
 
-pub struct Client {
+#[derive(Debug)]
+pub enum ClientError {
+    // The handshake with the node failed or was not answered
+    NoSession,
+    // The node did not answer the request within the retries
+    Timeout,
+    // The node refused the request
+    Refused(ErrorCode),
+    // The request was too large to send, or the node's reply did not parse
+    Protocol(ProtocolError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSession => write!(f, "failed to establish session"),
+            Self::Timeout => write!(f, "no reply from node"),
+            Self::Refused(code) => write!(f, "request refused: {}", code),
+            Self::Protocol(e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+pub struct Client<T: Transport = UdpTransport> {
     id: u16,
-    socket: UdpSocket,
+    // Signs every data request so the resulting transaction names this client
+    key_pair: Ed25519KeyPair,
+    transport: T,
+    timeout: Duration,
     remote_addr: String,
+    reassembler: Reassembler,
+    // Packets that arrived while waiting for an ack
+    inbox: VecDeque<Packet>,
+    sequence: u64,
+    // Encrypted session with the node, rekeyed once older than `SESSION_LIFETIME`
+    session: Option<Session>,
+    // Id of the node, learned from its handshake reply. Requests are signed for it
+    node_id: u16,
+    // Identity key of the node, pinned with `node_public_key` or else on the first
+    // handshake. Every packet from the node must be signed with it
+    node_public_key: Option<[u8; 32]>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Client {
     pub fn new(id: u16, timeout: Duration, remote_addr: &str) -> Self {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        socket.set_read_timeout(Some(timeout)).unwrap();
+        Self::with_transport(
+            id,
+            timeout,
+            remote_addr,
+            UdpTransport::bind("0.0.0.0:0").unwrap(),
+        )
+    }
+}
+
+impl<T: Transport> Client<T> {
+    pub fn with_transport(id: u16, timeout: Duration, remote_addr: &str, transport: T) -> Self {
+        Self::with_clock(id, timeout, remote_addr, transport, Arc::new(SystemClock))
+    }
+
+    // Stamps packets and signatures with `clock`, so the client can talk to nodes
+    // running on virtual time
+    pub fn with_clock(
+        id: u16,
+        timeout: Duration,
+        remote_addr: &str,
+        transport: T,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             id,
-            socket,
+            key_pair: keys::ephemeral(),
+            transport,
+            timeout,
             remote_addr: remote_addr.to_string(),
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT, clock.clone()),
+            inbox: VecDeque::new(),
+            sequence: initial_sequence(),
+            session: None,
+            node_id: 0,
+            node_public_key: None,
+            clock,
+        }
+    }
+
+    // Uses `key_pair` as the client identity instead of a key generated for this process
+    pub fn key_pair(mut self, key_pair: Ed25519KeyPair) -> Self {
+        self.key_pair = key_pair;
+        self
+    }
+
+    // Only trusts a node holding `public_key`, instead of the key it first presents
+    pub fn node_public_key(mut self, public_key: [u8; 32]) -> Self {
+        self.node_public_key = Some(public_key);
+        self
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    // Ok(None) when the node holds no value for the name
+    pub fn get_data(&mut self, data_name: &str) -> Result<Option<Vec<u8>>, ClientError> {
+        if !self.ensure_session() {
+            return Err(ClientError::NoSession);
+        }
+        let client_signature = ClientSignature::sign(
+            &self.key_pair,
+            self.id,
+            self.node_id,
+            CacheOperation::Get,
+            data_name,
+            [0; 32],
+            self.clock.now(),
+        );
+        let data_packet = match self.request(
+            PacketType::GetData,
+            RequestPayload::new(data_name.to_string(), client_signature, vec![]).as_bytes(),
+            PacketType::Data,
+        ) {
+            Ok(data_packet) => data_packet,
+            Err(ClientError::Refused(ErrorCode::NotFound)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        DataPayload::from_bytes(&data_packet.payload)
+            .map(|data_payload| Some(data_payload.data))
+            .map_err(ClientError::Protocol)
+    }
+
+    // None once the node answers that no block holds the transaction, or if it does not
+    // answer at all
+    pub fn get_proof(&mut self, transaction_hash: &[u8; 32]) -> Option<ProofPayload> {
+        let proof_packet = self
+            .request(
+                PacketType::GetProof,
+                GetProofPayload::new(*transaction_hash).as_bytes(),
+                PacketType::Proof,
+            )
+            .ok()?;
+        ProofPayload::from_bytes(&proof_packet.payload).ok()
+    }
+
+    // Returns the hash of the transaction recording the write once the node confirms it
+    pub fn set_data(&mut self, data_name: &str, data: &[u8]) -> Result<[u8; 32], ClientError> {
+        if !self.ensure_session() {
+            return Err(ClientError::NoSession);
+        }
+        let client_signature = ClientSignature::sign(
+            &self.key_pair,
+            self.id,
+            self.node_id,
+            CacheOperation::Set,
+            data_name,
+            sha256(data),
+            self.clock.now(),
+        );
+        self.confirmed_request(
+            PacketType::SetData,
+            RequestPayload::new(data_name.to_string(), client_signature, data.to_vec()).as_bytes(),
+        )
+    }
+
+    // Returns the hash of the transaction recording the delete once the node confirms it
+    pub fn delete_data(&mut self, data_name: &str) -> Result<[u8; 32], ClientError> {
+        if !self.ensure_session() {
+            return Err(ClientError::NoSession);
         }
+        let client_signature = ClientSignature::sign(
+            &self.key_pair,
+            self.id,
+            self.node_id,
+            CacheOperation::Delete,
+            data_name,
+            [0; 32],
+            self.clock.now(),
+        );
+        self.confirmed_request(
+            PacketType::DeleteData,
+            RequestPayload::new(data_name.to_string(), client_signature, vec![]).as_bytes(),
+        )
     }
 
-    pub fn get_data(&mut self, data_name: &str) -> Option<Vec<u8>> {
+    // Sends a write and waits for the node to confirm it, returning the transaction hash
+    fn confirmed_request(
+        &mut self,
+        packet_type: PacketType,
+        payload: Vec<u8>,
+    ) -> Result<[u8; 32], ClientError> {
+        let confirm_packet = self.request(packet_type, payload, PacketType::Confirm)?;
+        ConfirmPayload::from_bytes(&confirm_packet.payload)
+            .map(|confirm_payload| confirm_payload.transaction_hash)
+            .map_err(ClientError::Protocol)
+    }
+
+    fn request(
+        &mut self,
+        packet_type: PacketType,
+        payload: Vec<u8>,
+        response_type: PacketType,
+    ) -> Result<Packet, ClientError> {
+        if !self.ensure_session() {
+            println!("Failed to establish session");
+            return Err(ClientError::NoSession);
+        }
+        self.inbox.clear();
+        self.reassembler.expire();
+        // Retries resend the same packets so the node can recognise them as duplicates
+        let request_packet = Packet::new(self.id, 0, packet_type, payload);
+        let fragments = self
+            .prepare(&request_packet)
+            .map_err(ClientError::Protocol)?;
         let mut attempts = 0;
         while attempts <= MAX_RETRIES {
             if attempts > 0 {
-                thread::sleep(Duration::from_millis(ACK_TIMEOUT));
+                self.transport.sleep(Duration::from_millis(ACK_TIMEOUT));
             }
-            let data_packet = Packet::new(
-                self.id,
-                0,
-                PacketType::GetData,
-                DataPayload::new(data_name.to_string(), vec![]).as_bytes(),
-            );
-            self.socket
-                .send_to(&data_packet.as_bytes(), &self.remote_addr)
-                .unwrap();
+            if self.send_reliable(&fragments) {
+                if let Some(response_packet) = self.receive(response_type, request_packet.packet_id)
+                {
+                    // Retrying would not change a refusal
+                    if response_packet.packet_type == PacketType::Error {
+                        return Err(ErrorPayload::from_bytes(&response_packet.payload)
+                            .map_or_else(ClientError::Protocol, |error_payload| {
+                                ClientError::Refused(error_payload.code)
+                            }));
+                    }
+                    return Ok(response_packet);
+                }
+            }
+            attempts += 1;
+        }
+        Err(ClientError::Timeout)
+    }
 
-            let mut buffer = [0; PACKET_BUFFER_SIZE];
-            let Ok((size, _)) = self.socket.recv_from(&mut buffer) else {
-                attempts += 1;
-                continue;
-            };
+    // Runs a handshake with the node unless the current session is still fresh
+    fn ensure_session(&mut self) -> bool {
+        if self.session.as_ref().is_some_and(|session| {
+            session.age(self.clock.now()) < Duration::from_secs(SESSION_LIFETIME)
+        }) {
+            return true;
+        }
 
-            if let Some(ack_packet) = Packet::from_bytes(&buffer[..size]) {
-                if PacketType::from(ack_packet.packet_type) != PacketType::Ack {
-                    attempts += 1;
-                    continue;
+        let handshake = Handshake::new(self.clock.now());
+        let handshake_packet = Packet::new(
+            self.id,
+            0,
+            PacketType::Handshake,
+            HandshakePayload::new(
+                handshake.ephemeral_key,
+                false,
+                self.public_key().try_into().unwrap(),
+            )
+            .as_bytes(),
+        );
+        // Signed so the node knows which client key the session belongs to
+        let Ok(mut fragments) = self.prepare(&handshake_packet) else {
+            return false;
+        };
+        for fragment in &mut fragments {
+            fragment.signature = self
+                .key_pair
+                .sign(&fragment.signing_bytes())
+                .as_ref()
+                .try_into()
+                .unwrap();
+        }
+        self.inbox.clear();
+        if !self.send_reliable(&fragments) {
+            return false;
+        }
+        let Some(reply_packet) = self.receive(PacketType::Handshake, handshake_packet.packet_id)
+        else {
+            return false;
+        };
+        let Ok(reply) = HandshakePayload::from_bytes(&reply_packet.payload) else {
+            return false;
+        };
+        if self
+            .node_public_key
+            .is_some_and(|public_key| public_key != reply.public_key)
+        {
+            return false;
+        }
+        let Some(key) = handshake.finish(self.id, reply_packet.src, &reply.ephemeral_key, true)
+        else {
+            return false;
+        };
+        self.node_public_key = Some(reply.public_key);
+        self.node_id = reply_packet.src;
+        let now = self.clock.now();
+        match &mut self.session {
+            Some(session) => session.rotate(key, now),
+            None => self.session = Some(Session::new(key, now)),
+        }
+        true
+    }
+
+    // Splits `packet` into fragments, numbering each one and sealing it when a
+    // session is established
+    fn prepare(&mut self, packet: &Packet) -> Result<Vec<Packet>, ProtocolError> {
+        Ok(packet
+            .fragment()?
+            .into_iter()
+            .map(|mut fragment| {
+                fragment.sequence = self.next_sequence();
+                fragment.timestamp = self.clock.now();
+                if let Some(session) = &self.session {
+                    if fragment.packet_type != PacketType::Handshake {
+                        session.seal(&mut fragment);
+                    }
                 }
-            } else {
-                attempts += 1;
-                continue;
-            }
+                fragment
+            })
+            .collect())
+    }
 
-            let Ok((size, _)) = self.socket.recv_from(&mut buffer) else {
-                attempts += 1;
-                continue;
-            };
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    // Sends every fragment, retrying each until the node acknowledges it
+    fn send_reliable(&mut self, fragments: &[Packet]) -> bool {
+        fragments
+            .iter()
+            .all(|fragment| self.send_with_ack(fragment))
+    }
+
+    fn send_with_ack(&mut self, packet: &Packet) -> bool {
+        let mut attempts = 0;
+        while attempts <= MAX_RETRIES {
+            if attempts > 0 {
+                self.transport.sleep(Duration::from_millis(ACK_TIMEOUT));
+            }
+            self.transport
+                .send_to(&packet.as_bytes(), &self.remote_addr)
+                .unwrap();
 
-            if let Some(data_packet) = Packet::from_bytes(&buffer[..size]) {
-                if PacketType::from(data_packet.packet_type) != PacketType::Data {
-                    attempts += 1;
+            while let Some(received) = self.recv_packet() {
+                if received.packet_type != PacketType::Ack {
+                    self.inbox.push_back(received);
                     continue;
                 }
-                let ack_packet = Packet::new(
-                    self.id,
-                    0,
-                    PacketType::Ack,
-                    AckPayload::new(data_packet.packet_id).as_bytes(),
-                );
-                self.socket
-                    .send_to(&ack_packet.as_bytes(), &self.remote_addr)
-                    .unwrap();
-
-                let data_payload = DataPayload::from_bytes(&data_packet.payload);
-                return Some(data_payload.data);
-            } else {
-                attempts += 1;
-                continue;
+                if AckPayload::from_bytes(&received.payload)
+                    .is_ok_and(|ack_payload| ack_payload.packet_id == packet.packet_id)
+                {
+                    return true;
+                }
             }
+            attempts += 1;
         }
-        None
+        false
     }
 
-    pub fn set_data(&mut self, data_name: &str, data: &[u8]) {
-        let mut attempts = 0;
-        while attempts <= MAX_RETRIES {
-            if attempts > 0 {
-                thread::sleep(Duration::from_millis(ACK_TIMEOUT));
+    // Waits for a packet of `response_type`, or an Error refusing request `request_id`,
+    // acking and reassembling whatever arrives. Other errors are reported and skipped
+    fn receive(&mut self, response_type: PacketType, request_id: u32) -> Option<Packet> {
+        loop {
+            let packet = match self.inbox.pop_front() {
+                Some(packet) => packet,
+                None => self.recv_packet()?,
+            };
+            if packet.packet_type == PacketType::Ack {
+                continue;
             }
-            let data_packet = Packet::new(
+
+            let mut ack_packet = Packet::new(
                 self.id,
                 0,
-                PacketType::SetData,
-                DataPayload::new(data_name.to_string(), data.to_vec()).as_bytes(),
+                PacketType::Ack,
+                AckPayload::new(packet.packet_id).as_bytes(),
             );
-            self.socket
-                .send_to(&data_packet.as_bytes(), &self.remote_addr)
+            ack_packet.sequence = self.next_sequence();
+            ack_packet.timestamp = self.clock.now();
+            self.transport
+                .send_to(&ack_packet.as_bytes(), &self.remote_addr)
                 .unwrap();
 
-            let mut buffer = [0; PACKET_BUFFER_SIZE];
-            let Ok((size, _)) = self.socket.recv_from(&mut buffer) else {
-                attempts += 1;
-                println!("Failed to receive ack packet");
-                continue;
+            let packet = if packet.packet_type == PacketType::Fragment {
+                let Ok(Some(packet)) = self.reassembler.insert(&packet) else {
+                    continue;
+                };
+                packet
+            } else {
+                packet
             };
-
-            if let Some(ack_packet) = Packet::from_bytes(&buffer[..size]) {
-                if PacketType::from(ack_packet.packet_type) != PacketType::Ack {
-                    attempts += 1;
+            if packet.packet_type == PacketType::Error {
+                let Ok(error_payload) = ErrorPayload::from_bytes(&packet.payload) else {
                     continue;
+                };
+                println!("Request refused: {}", error_payload);
+                if error_payload.request_id == request_id {
+                    return Some(packet);
                 }
-                return;
-            } else {
-                attempts += 1;
                 continue;
             }
+            // Confirmations of earlier requests are late duplicates
+            if packet.packet_type == PacketType::Confirm
+                && !ConfirmPayload::from_bytes(&packet.payload)
+                    .is_ok_and(|confirm_payload| confirm_payload.request_id == request_id)
+            {
+                continue;
+            }
+            if packet.packet_type == response_type {
+                return Some(packet);
+            }
+        }
+    }
+
+    // Returns the next packet that parses, is signed by the node and, if encrypted,
+    // opens with the session key. Until the node's key is known only acks and handshake
+    // replies signed with the key they carry are let through, and once a session is up
+    // only acks and handshakes may arrive in the clear
+    fn recv_packet(&mut self) -> Option<Packet> {
+        let mut buffer = [0; PACKET_BUFFER_SIZE];
+        loop {
+            let (size, _) = self.transport.recv_from(&mut buffer, self.timeout).ok()?;
+            let Ok(mut packet) = Packet::from_bytes(&buffer[..size]) else {
+                continue;
+            };
+            let signer = match (self.node_public_key, packet.packet_type) {
+                (Some(public_key), _) => Some(public_key),
+                (None, PacketType::Handshake) => HandshakePayload::from_bytes(&packet.payload)
+                    .ok()
+                    .map(|reply| reply.public_key),
+                _ => None,
+            };
+            let trusted = match signer {
+                Some(public_key) => packet.verify_signature(&public_key),
+                None => packet.packet_type == PacketType::Ack,
+            };
+            if !trusted {
+                continue;
+            }
+            if packet.encrypted {
+                if self
+                    .session
+                    .as_ref()
+                    .is_some_and(|session| session.open(&mut packet))
+                {
+                    return Some(packet);
+                }
+            } else if self.session.is_none()
+                || matches!(packet.packet_type, PacketType::Ack | PacketType::Handshake)
+            {
+                return Some(packet);
+            }
         }
     }
 }
@@ -0,0 +1,56 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Source of wall-clock time for nodes, clients and the blocks and transactions they
+// create, so simulations can run them on virtual time
+pub trait Clock: fmt::Debug + Send + Sync {
+    // Milliseconds since the Unix epoch
+    fn now(&self) -> u64;
+
+    fn now_secs(&self) -> u64 {
+        self.now() / 1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+// Clock that only moves when advanced. Clones share the same time
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new(start: u64) -> Self {
+        Self {
+            millis: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.millis
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,333 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::acl::{AclRule, Permission};
+use crate::block::BLOCK_PERIOD;
+use crate::discovery::GOSSIP_PERIOD;
+use crate::election::{ELECTION_TIMEOUT, HEARTBEAT_PERIOD};
+use crate::node::{ATLAS_PORT, WEB_PORT};
+use crate::peer::{DEAD_TIMEOUT, SUSPECT_TIMEOUT};
+use crate::protocol::{ACK_TIMEOUT, MAX_RETRIES};
+use crate::session::SESSION_LIFETIME;
+use crate::utils::parse_hex;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    // An `acl` rule names a client whose key is not pinned in `clients`, so any
+    // client could claim its id
+    UnpinnedClient(u16),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to read config: {}", e),
+            Self::Parse(e) => write!(f, "failed to parse config: {}", e),
+            Self::UnpinnedClient(id) => {
+                write!(f, "acl names client {} but its key is not in clients", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeedPeer {
+    pub id: u16,
+    pub address: String,
+}
+
+// Node or client admitted to the network, identified by its hex-encoded Ed25519 public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: u16,
+    pub public_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    pub id: u16,
+    pub bind_address: String,
+    pub atlas_port: u16,
+    pub web_port: u16,
+    pub web_root: String,
+    // Whether the dashboard is served on `web_port`
+    pub web: bool,
+    // Nodes probed on startup to join the network
+    pub seed_peers: Vec<SeedPeer>,
+    // Node that starts as leader without waiting for an election
+    pub leader: Option<u16>,
+    // Whether this node may stand for election
+    pub candidate: bool,
+    pub block_period: u64, // seconds
+    pub ack_timeout: u64,  // milliseconds
+    pub max_retries: u8,
+    pub heartbeat_period: u64, // milliseconds
+    pub election_timeout: u64, // milliseconds
+    pub read_timeout: u64,     // milliseconds
+    // Silence after which a peer is suspected, then declared dead and no longer sent to
+    pub suspect_timeout: u64, // milliseconds
+    pub dead_timeout: u64,    // milliseconds
+    // How often known peers are gossiped to every peer, and announced over multicast
+    pub gossip_period: u64, // milliseconds
+    // Multicast group, such as 239.255.70.17:7018, for finding nodes on the local
+    // network. Discovery relies on seed peers and gossip alone when unset
+    pub discovery_group: Option<String>,
+    // Directory for the block store, blocks are kept in memory only when unset
+    pub data_dir: Option<String>,
+    // Directory for the durable cache, the cache is in-memory only when unset
+    pub cache_dir: Option<String>,
+    // PKCS#8 file holding the node's identity key, generated on first run. A fresh
+    // key is used on every launch when unset
+    pub key_path: Option<String>,
+    // Nodes allowed to join as peers. Any node is admitted when empty
+    pub members: Vec<Member>,
+    // Clients whose signed requests are accepted. Any client key is accepted when empty
+    pub clients: Vec<Member>,
+    // Access rules for client requests. Every request is allowed when empty, otherwise
+    // a request needs a rule granting it
+    pub acl: Vec<AclRule>,
+    // Whether requests denied by `acl` are recorded on the chain
+    pub audit_denied: bool,
+    pub session_lifetime: u64, // seconds
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            bind_address: "127.0.0.1".to_string(),
+            atlas_port: ATLAS_PORT,
+            web_port: WEB_PORT,
+            web_root: "web/dist".to_string(),
+            web: true,
+            seed_peers: Vec::new(),
+            leader: None,
+            candidate: true,
+            block_period: BLOCK_PERIOD,
+            ack_timeout: ACK_TIMEOUT,
+            max_retries: MAX_RETRIES,
+            heartbeat_period: HEARTBEAT_PERIOD,
+            election_timeout: ELECTION_TIMEOUT,
+            read_timeout: 10,
+            suspect_timeout: SUSPECT_TIMEOUT,
+            dead_timeout: DEAD_TIMEOUT,
+            gossip_period: GOSSIP_PERIOD,
+            discovery_group: None,
+            data_dir: None,
+            cache_dir: None,
+            key_path: None,
+            members: Vec::new(),
+            clients: Vec::new(),
+            acl: Vec::new(),
+            audit_denied: false,
+            session_lifetime: SESSION_LIFETIME,
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn new(id: u16, bind_address: &str) -> Self {
+        Self {
+            id,
+            bind_address: bind_address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: Self = serde_json::from_str(&contents).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Refuses `acl` rules for clients whose key is not pinned. Rules for every client
+    // need no pinned keys
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self
+            .acl
+            .iter()
+            .filter_map(|rule| rule.client)
+            .find(|id| !self.clients.iter().any(|client| client.id == *id))
+        {
+            Some(id) => Err(ConfigError::UnpinnedClient(id)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn atlas_address(&self) -> String {
+        format!("{}:{}", self.bind_address, self.atlas_port)
+    }
+
+    pub fn web_address(&self) -> String {
+        format!("{}:{}", self.bind_address, self.web_port)
+    }
+
+    pub fn atlas_port(mut self, port: u16) -> Self {
+        self.atlas_port = port;
+        self
+    }
+
+    pub fn web_port(mut self, port: u16) -> Self {
+        self.web_port = port;
+        self
+    }
+
+    pub fn web_root(mut self, web_root: &str) -> Self {
+        self.web_root = web_root.to_string();
+        self
+    }
+
+    pub fn web(mut self, web: bool) -> Self {
+        self.web = web;
+        self
+    }
+
+    pub fn seed_peer(mut self, id: u16, address: &str) -> Self {
+        self.seed_peers.push(SeedPeer {
+            id,
+            address: address.to_string(),
+        });
+        self
+    }
+
+    pub fn leader(mut self, leader: u16) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    pub fn candidate(mut self, candidate: bool) -> Self {
+        self.candidate = candidate;
+        self
+    }
+
+    pub fn block_period(mut self, seconds: u64) -> Self {
+        self.block_period = seconds;
+        self
+    }
+
+    pub fn ack_timeout(mut self, milliseconds: u64) -> Self {
+        self.ack_timeout = milliseconds;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn heartbeat_period(mut self, milliseconds: u64) -> Self {
+        self.heartbeat_period = milliseconds;
+        self
+    }
+
+    pub fn election_timeout(mut self, milliseconds: u64) -> Self {
+        self.election_timeout = milliseconds;
+        self
+    }
+
+    pub fn read_timeout(mut self, milliseconds: u64) -> Self {
+        self.read_timeout = milliseconds;
+        self
+    }
+
+    pub fn suspect_timeout(mut self, milliseconds: u64) -> Self {
+        self.suspect_timeout = milliseconds;
+        self
+    }
+
+    pub fn dead_timeout(mut self, milliseconds: u64) -> Self {
+        self.dead_timeout = milliseconds;
+        self
+    }
+
+    pub fn gossip_period(mut self, milliseconds: u64) -> Self {
+        self.gossip_period = milliseconds;
+        self
+    }
+
+    pub fn discovery_group(mut self, group: &str) -> Self {
+        self.discovery_group = Some(group.to_string());
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: &str) -> Self {
+        self.data_dir = Some(data_dir.to_string());
+        self
+    }
+
+    pub fn cache_dir(mut self, cache_dir: &str) -> Self {
+        self.cache_dir = Some(cache_dir.to_string());
+        self
+    }
+
+    pub fn key_path(mut self, key_path: &str) -> Self {
+        self.key_path = Some(key_path.to_string());
+        self
+    }
+
+    pub fn member(mut self, id: u16, public_key: &str) -> Self {
+        self.members.push(Member {
+            id,
+            public_key: public_key.to_string(),
+        });
+        self
+    }
+
+    pub fn client(mut self, id: u16, public_key: &str) -> Self {
+        self.clients.push(Member {
+            id,
+            public_key: public_key.to_string(),
+        });
+        self
+    }
+
+    pub fn acl_rule(mut self, rule: AclRule) -> Self {
+        self.acl.push(rule);
+        self
+    }
+
+    pub fn audit_denied(mut self, audit_denied: bool) -> Self {
+        self.audit_denied = audit_denied;
+        self
+    }
+
+    pub fn session_lifetime(mut self, seconds: u64) -> Self {
+        self.session_lifetime = seconds;
+        self
+    }
+
+    // Whether `public_key` is pinned for node `id`, or membership is open
+    pub fn is_member(&self, id: u16, public_key: &[u8]) -> bool {
+        self.members.is_empty()
+            || self.members.iter().any(|member| {
+                member.id == id && parse_hex(&member.public_key).as_deref() == Some(public_key)
+            })
+    }
+
+    // Whether `public_key` is pinned for client `id`, or any client key is accepted
+    pub fn is_client(&self, id: u16, public_key: &[u8]) -> bool {
+        self.clients.is_empty()
+            || self.clients.iter().any(|client| {
+                client.id == id && parse_hex(&client.public_key).as_deref() == Some(public_key)
+            })
+    }
+
+    // Whether `acl` lets client `id` use `permission` on `data_name`. A rule naming a
+    // client whose key is not pinned never applies
+    pub fn is_allowed(&self, id: u16, data_name: &str, permission: Permission) -> bool {
+        self.acl.is_empty()
+            || self.acl.iter().any(|rule| {
+                rule.client
+                    .is_none_or(|client| self.clients.iter().any(|pinned| pinned.id == client))
+                    && rule.allows(id, data_name, permission)
+            })
+    }
+}
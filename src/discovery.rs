@@ -0,0 +1,61 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+pub const DISCOVERY_GROUP: &str = "239.255.70.17:7018";
+pub const GOSSIP_PERIOD: u64 = 10000; // milliseconds
+
+// UDP multicast announcements for finding nodes on the local network. Only one
+// process per host can listen on the group port, so a node that finds it taken still
+// announces itself but does not listen
+pub struct Discovery {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    listening: bool,
+}
+
+impl Discovery {
+    pub fn join(group: &str) -> io::Result<Self> {
+        let group: SocketAddrV4 = group
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid multicast group"))?;
+        if !group.ip().is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a multicast address", group.ip()),
+            ));
+        }
+        let (socket, listening) = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port())) {
+            Ok(socket) => {
+                socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+                (socket, true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                (UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?, false)
+            }
+            Err(e) => return Err(e),
+        };
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            group,
+            listening,
+        })
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    pub fn announce(&self, bytes: &[u8]) -> io::Result<()> {
+        self.socket.send_to(bytes, self.group).map(|_| ())
+    }
+
+    // Next waiting announcement and where it came from, without blocking
+    pub fn receive(&self, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        if !self.listening {
+            return None;
+        }
+        self.socket.recv_from(buffer).ok()
+    }
+}
@@ -0,0 +1,184 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+use crate::utils::SplitMix64;
+
+pub const HEARTBEAT_PERIOD: u64 = 1000; // milliseconds
+pub const ELECTION_TIMEOUT: u64 = 3000; // milliseconds, randomized up to twice this
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Raft-style term and vote bookkeeping; the node drives it and sends the packets
+#[derive(Debug)]
+pub struct Election {
+    pub term: u32,
+    pub role: Role,
+    pub leader: Option<u16>,
+    voted_for: Option<u16>,
+    votes: HashSet<u16>,
+    last_heartbeat: u64,      // milliseconds
+    last_heartbeat_sent: u64, // milliseconds
+    election_timeout: u64,    // milliseconds
+    heartbeat_period: u64,    // milliseconds
+    base_election_timeout: u64,
+    candidate: bool,
+    clock: Arc<dyn Clock>,
+    // Draws election timeouts from a seed instead of the system RNG when set
+    rng: Option<SplitMix64>,
+}
+
+impl Default for Election {
+    fn default() -> Self {
+        Self::new(
+            HEARTBEAT_PERIOD,
+            ELECTION_TIMEOUT,
+            true,
+            Arc::new(SystemClock),
+        )
+    }
+}
+
+impl Election {
+    pub fn new(
+        heartbeat_period: u64,
+        election_timeout: u64,
+        candidate: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        let mut election = Self {
+            term: 0,
+            role: Role::Follower,
+            leader: None,
+            voted_for: None,
+            votes: HashSet::new(),
+            last_heartbeat: now,
+            last_heartbeat_sent: now,
+            election_timeout: 0,
+            heartbeat_period,
+            base_election_timeout: election_timeout,
+            candidate,
+            clock,
+            rng: None,
+        };
+        election.election_timeout = election.random_timeout();
+        election
+    }
+
+    // Makes election timeouts reproducible, for simulations
+    pub fn seeded(mut self, seed: u64) -> Self {
+        self.rng = Some(SplitMix64(seed));
+        self.election_timeout = self.random_timeout();
+        self
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn election_due(&self) -> bool {
+        self.candidate
+            && self.role != Role::Leader
+            && self.clock.now().saturating_sub(self.last_heartbeat) >= self.election_timeout
+    }
+
+    pub fn heartbeat_due(&self) -> bool {
+        self.role == Role::Leader
+            && self.clock.now().saturating_sub(self.last_heartbeat_sent) >= self.heartbeat_period
+    }
+
+    pub fn heartbeat_sent(&mut self) {
+        self.last_heartbeat_sent = self.clock.now();
+    }
+
+    pub fn start_election(&mut self, id: u16) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(id);
+        self.votes.clear();
+        self.votes.insert(id);
+        self.reset_timer();
+    }
+
+    // Steps down to follower when a higher term is seen, returns whether it did
+    pub fn observe_term(&mut self, term: u32) -> bool {
+        if term <= self.term {
+            return false;
+        }
+        self.term = term;
+        self.role = Role::Follower;
+        self.leader = None;
+        self.voted_for = None;
+        self.votes.clear();
+        true
+    }
+
+    pub fn follow(&mut self, leader: u16, term: u32) {
+        self.observe_term(term);
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.reset_timer();
+    }
+
+    pub fn grant_vote(&mut self, candidate: u16, term: u32, up_to_date: bool) -> bool {
+        self.observe_term(term);
+        if term < self.term || !up_to_date {
+            return false;
+        }
+        if self
+            .voted_for
+            .is_some_and(|voted_for| voted_for != candidate)
+        {
+            return false;
+        }
+        self.voted_for = Some(candidate);
+        self.reset_timer();
+        true
+    }
+
+    // Records a granted vote, returns true once the candidate holds a majority of `cluster_size`
+    pub fn record_vote(&mut self, voter: u16, term: u32, cluster_size: usize) -> bool {
+        if self.role != Role::Candidate || term != self.term {
+            return false;
+        }
+        self.votes.insert(voter);
+        self.has_majority(cluster_size)
+    }
+
+    pub fn has_majority(&self, cluster_size: usize) -> bool {
+        self.role == Role::Candidate && self.votes.len() * 2 > cluster_size
+    }
+
+    pub fn become_leader(&mut self, id: u16) {
+        self.role = Role::Leader;
+        self.leader = Some(id);
+        self.votes.clear();
+    }
+
+    fn reset_timer(&mut self) {
+        self.last_heartbeat = self.clock.now();
+        self.election_timeout = self.random_timeout();
+    }
+
+    fn random_timeout(&mut self) -> u64 {
+        let base = self.base_election_timeout;
+        let random = match &mut self.rng {
+            Some(rng) => rng.next_u64(),
+            None => {
+                let mut random_bytes = [0u8; 2];
+                SystemRandom::new().fill(&mut random_bytes).unwrap();
+                u16::from_le_bytes(random_bytes) as u64
+            }
+        };
+        base + random % base.max(1)
+    }
+}
@@ -0,0 +1,52 @@
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Generates an Ed25519 key pair and writes it to `path` as PKCS#8, readable only by
+// the owner. Fails if the file already exists so a node identity is never overwritten
+pub fn generate(path: impl AsRef<Path>) -> io::Result<Ed25519KeyPair> {
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| io::Error::other("failed to generate key pair"))?;
+
+    if let Some(dir) = path.as_ref().parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(pkcs8_bytes.as_ref())?;
+    file.sync_all()?;
+
+    parse(pkcs8_bytes.as_ref())
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Ed25519KeyPair> {
+    parse(&fs::read(path)?)
+}
+
+// Loads the key pair at `path`, generating it on first run. Returns whether it was generated
+pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<(Ed25519KeyPair, bool)> {
+    match load(&path) {
+        Ok(key_pair) => Ok((key_pair, false)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((generate(path)?, true)),
+        Err(e) => Err(e),
+    }
+}
+
+// Key pair that only lives as long as the process
+pub fn ephemeral() -> Ed25519KeyPair {
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap()
+}
+
+fn parse(pkcs8_bytes: &[u8]) -> io::Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8(pkcs8_bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
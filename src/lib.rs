@@ -1,8 +1,19 @@
+pub mod acl;
 pub mod block;
 pub mod cache;
 pub mod client;
+pub mod clock;
+pub mod config;
+pub mod discovery;
+pub mod election;
+pub mod keys;
 pub mod node;
+pub mod peer;
 pub mod protocol;
+pub mod session;
+pub mod simulation;
+pub mod store;
 pub mod transaction;
+pub mod transport;
 pub mod utils;
 pub mod web;
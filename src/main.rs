@@ -1,40 +1,62 @@
-use std::{thread, time::Duration};
+use std::{env, process, thread, time::Duration};
 
-use atlas::{
-    client::Client,
-    node::{Node, ATLAS_PORT},
-};
+use atlas::{client::Client, config::NodeConfig, keys, node::Node, utils::hex_string};
+use ring::signature::KeyPair;
 
 fn main() {
-    let orchestrator = thread::spawn(move || {
-        let mut orchestrator = Node::new(0, "127.0.0.1");
-        orchestrator.run();
-    });
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("keygen") => {
+            let Some(key_path) = args.get(2) else {
+                eprintln!("usage: atlas keygen <key-file>");
+                process::exit(1);
+            };
+            let key_pair = keys::generate(key_path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", key_path, e);
+                process::exit(1);
+            });
+            println!("{}", hex_string(key_pair.public_key().as_ref()));
+        }
+        Some(config_path) => {
+            let config = NodeConfig::from_file(config_path).unwrap_or_else(|e| {
+                eprintln!("{}: {}", config_path, e);
+                process::exit(1);
+            });
+            Node::new(config).run();
+        }
+        None => run_demo(),
+    }
+}
 
-    let node1 = thread::spawn(move || {
-        let mut node1 = Node::new(1, "127.0.0.2");
-        thread::sleep(Duration::from_secs(1));
-        node1.run();
-    });
+// Two nodes on loopback and a client writing satellite positions
+fn run_demo() {
+    let orchestrator_config = NodeConfig::new(0, "127.0.0.1").leader(0);
+    let orchestrator_address = orchestrator_config.atlas_address();
+    let node1_config = NodeConfig::new(1, "127.0.0.2").seed_peer(0, orchestrator_address.as_str());
+
+    let orchestrator = Node::spawn(orchestrator_config).expect("Failed to start orchestrator");
+    thread::sleep(Duration::from_secs(1));
+    let node1 = Node::spawn(node1_config).expect("Failed to start node 1");
 
     let client = thread::spawn(move || {
-        let mut client = Client::new(
-            2,
-            Duration::from_secs(1),
-            format!("127.0.0.1:{}", ATLAS_PORT).as_str(),
-        );
+        let mut client = Client::new(2, Duration::from_secs(1), orchestrator_address.as_str());
         let mut sat = 0;
 
         loop {
             thread::sleep(Duration::from_secs(1));
-            client.set_data(&format!("/satellite/{}", sat % 10), "world".as_bytes());
+            if let Err(e) = client.set_data(&format!("/satellite/{}", sat % 10), "world".as_bytes())
+            {
+                println!("Failed to set data: {}", e);
+            }
             thread::sleep(Duration::from_secs(1));
-            client.get_data(&format!("/satellite/{}", sat % 10));
+            if let Err(e) = client.get_data(&format!("/satellite/{}", sat % 10)) {
+                println!("Failed to get data: {}", e);
+            }
             sat += 1;
         }
     });
 
-    orchestrator.join().expect("Orchestrator thread failed");
-    node1.join().expect("Node 1 thread failed");
     client.join().expect("Client thread failed");
+    node1.shutdown().expect("Node 1 thread failed");
+    orchestrator.shutdown().expect("Orchestrator thread failed");
 }
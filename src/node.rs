@@ -1,123 +1,769 @@
-use crate::block::{Block, BLOCK_PERIOD};
+use crate::block::{Block, ValidationError};
 use crate::cache::CacheOperation;
+use crate::cache::{Cache, DurableCache, InMemoryCache};
+use crate::clock::{Clock, SystemClock};
+use crate::config::NodeConfig;
+use crate::discovery::Discovery;
+use crate::election::{Election, Role};
+use crate::keys;
+use crate::peer::{Peer, PeerState};
 use crate::protocol::{
-    AckPayload, BlockPayload, ChainPayload, DataPayload, Packet, PacketType, ProbePayload,
-    SyncPayload, TransactionPayload, ACK_TIMEOUT, MAX_RETRIES, PACKET_BUFFER_SIZE,
+    initial_sequence, AckPayload, BlockPayload, ChainPayload, ConfirmPayload, DataPayload,
+    ErrorCode, ErrorPayload, GetChainPayload, GetProofPayload, HandshakePayload, HeartbeatPayload,
+    Packet, PacketType, PeerEntry, PeerListPayload, ProbePayload, ProofPayload, ProtocolError,
+    Reassembler, Replay, ReplayFilter, ReplicatePayload, RequestPayload, RequestVotePayload,
+    SyncPayload, TransactionPayload, VotePayload, CHAIN_PAGE_BYTES, CHAIN_PAGE_SIZE,
+    MAX_PACKET_AGE, PACKET_BUFFER_SIZE, REASSEMBLY_TIMEOUT, REPLAY_WINDOW,
 };
-use crate::transaction::Transaction;
-use crate::utils::hex_string;
+use crate::session::{Handshake, Session, HANDSHAKE_TIMEOUT};
+use crate::store::{BlockStore, FileBlockStore, InMemoryBlockStore};
+use crate::transaction::{ClientSignature, Transaction, MAX_REQUEST_AGE};
+use crate::transport::{Transport, UdpTransport};
+use crate::utils::{hex_string, parse_hex, sha256};
 use crate::web::{WebServer, WebSignal};
-use crate::{cache::Cache, cache::InMemoryCache};
-use ring::rand;
-use ring::signature::{self, Ed25519KeyPair, KeyPair, Signature, UnparsedPublicKey};
-use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::sync::mpsc::Receiver;
+use ring::aead::LessSafeKey;
+use ring::signature::{Ed25519KeyPair, KeyPair, Signature};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 pub const ATLAS_PORT: u16 = 7017;
 pub const WEB_PORT: u16 = 7010;
+// Data packets kept for a peer while its session is set up, past which the oldest
+// are dropped
+const MAX_HELD_PACKETS: usize = 256;
 
-pub struct Node {
+// Snapshot of a running node, as reported by `NodeHandle::status`
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatus {
+    pub id: u16,
+    pub role: Role,
+    pub term: u32,
+    pub leader: Option<u16>,
+    pub chain_height: usize,
+    pub pending_transactions: usize,
+    pub peers: usize,
+    pub dropped_packets: u64,
+    // Packets the transport refused to send, such as to an unreachable address
+    pub failed_sends: u64,
+}
+
+enum NodeCommand {
+    Status(Sender<NodeStatus>),
+    Shutdown,
+}
+
+// Controls a node running on its own thread. Dropping the handle shuts the node down
+pub struct NodeHandle {
+    commands: Sender<NodeCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NodeHandle {
+    // None once the node has stopped
+    pub fn status(&self) -> Option<NodeStatus> {
+        let (reply_tx, reply_rx) = channel();
+        self.commands.send(NodeCommand::Status(reply_tx)).ok()?;
+        reply_rx.recv().ok()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.is_finished())
+    }
+
+    // Stops the node gracefully and waits for its thread to exit
+    pub fn shutdown(mut self) -> thread::Result<()> {
+        self.stop()
+    }
+
+    // Waits for the node's thread to exit without asking it to stop
+    pub fn join(mut self) -> thread::Result<()> {
+        self.thread.take().map_or(Ok(()), JoinHandle::join)
+    }
+
+    fn stop(&mut self) -> thread::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        let _ = self.commands.send(NodeCommand::Shutdown);
+        thread.join()
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+pub struct Node<T: Transport = UdpTransport> {
     id: u16,
-    socket: UdpSocket,
+    config: NodeConfig,
+    transport: T,
+    // Addresses of admitted peers
     addr_table: HashMap<u16, String>,
-    pending_acks: HashMap<u32, (u8, Instant, Packet)>,
-    web_server: Arc<WebServer>,
+    // Where each client's last request came from, kept apart so a client can never
+    // redirect traffic meant for a peer
+    client_addrs: HashMap<u16, String>,
+    // Key each client's session was opened with, which signs its requests
+    client_keys: HashMap<u16, [u8; 32]>,
+    // Retry count and send time in milliseconds of each packet awaiting an ack
+    pending_acks: HashMap<u32, (u8, u64, Packet)>,
+    // Data packets for peers we have no session with yet, sent once there is one
+    held_packets: HashMap<u16, VecDeque<Packet>>,
+    reassembler: Reassembler,
+    replay_filter: ReplayFilter,
+    sequence: u64,
+    dropped_packets: u64,
+    failed_sends: u64,
+    rejected_peers: u64,
+    // Serves the dashboard, unless disabled in the config
+    web_server: Option<Arc<WebServer>>,
     web_signal_rx: Receiver<WebSignal>,
-    cache: InMemoryCache,
+    cache: Box<dyn Cache>,
     key_pair: Ed25519KeyPair,
+    // Keys of every peer admitted, kept after it leaves
     peer_public_keys: HashMap<u16, Vec<u8>>,
-    leader: u16,
+    // Liveness of every connected peer node
+    peers: HashMap<u16, Peer>,
+    // Keys gossiped for nodes probed but not yet admitted, which their Sync must match
+    gossiped_keys: HashMap<u16, [u8; 32]>,
+    last_ping: u64,   // milliseconds
+    last_gossip: u64, // milliseconds
+    // Multicast announcements, when a discovery group is configured
+    discovery: Option<Discovery>,
+    election: Election,
+    sessions: HashMap<u16, Session>,
+    // Handshakes we opened, waiting for the peer's ephemeral key
+    handshakes: HashMap<u16, Handshake>,
     pending_transactions: HashMap<[u8; 32], Transaction>,
+    // Hashes of the transactions on the chain, so a resent one is not taken as pending
+    sealed_transactions: HashSet<[u8; 32]>,
+    // Latest write or delete of each name, on the chain, pending or replicated, with its
+    // timestamp and transaction hash. Kept up to date as transactions arrive so
+    // tombstones need no scan of the chain, and replicated writes are not applied
+    // over newer ones
+    latest_writes: HashMap<String, (CacheOperation, u64, [u8; 32])>,
+    // Client signatures accepted within the last `MAX_REQUEST_AGE`, with their
+    // timestamps, so no request is served twice
+    seen_requests: HashMap<[u8; 64], u64>,
     chain: Vec<Block>,
+    // Blocks of a competing chain being downloaded page by page from height 0
+    fork_chain: Vec<Block>,
+    // Blocks a majority holds, which a competing chain may not replace
+    committed_height: usize,
+    // Chain height each peer last reported holding, while leader
+    match_heights: HashMap<u16, usize>,
+    store: Box<dyn BlockStore>,
+    clock: Arc<dyn Clock>,
+}
+
+// Prefixes an error with the setup step that failed
+fn context(step: &'static str) -> impl FnOnce(io::Error) -> io::Error {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", step, e))
 }
 
 impl Node {
-    pub fn new(id: u16, ip_address: &str) -> Self {
-        let rng = rand::SystemRandom::new();
-        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-        let socket = UdpSocket::bind(format!("{}:{}", ip_address, ATLAS_PORT))
-            .expect("Failed to bind to address");
-        socket
-            .set_read_timeout(Some(Duration::from_millis(10)))
-            .unwrap();
-        let (web_server, rx) = WebServer::new(&format!("{}:{}", ip_address, WEB_PORT));
+    pub fn new(config: NodeConfig) -> Self {
+        let transport =
+            UdpTransport::bind(&config.atlas_address()).expect("Failed to bind to address");
+        Self::with_transport(config, transport)
+    }
+
+    // Runs a node on a new thread, returning a handle to query and stop it. Returns
+    // once the node is set up, with the error if that failed
+    pub fn spawn(config: NodeConfig) -> io::Result<NodeHandle> {
+        let transport = UdpTransport::bind(&config.atlas_address())?;
+        let (commands_tx, commands_rx) = channel();
+        let (setup_tx, setup_rx) = channel();
+        let thread = thread::spawn(move || {
+            match Self::try_with_clock(config, transport, Arc::new(SystemClock)) {
+                Ok(mut node) => {
+                    let _ = setup_tx.send(Ok(()));
+                    node.serve(commands_rx);
+                }
+                Err(e) => {
+                    let _ = setup_tx.send(Err(e));
+                }
+            }
+        });
+        let setup = setup_rx
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("node thread panicked during setup")));
+        if let Err(e) = setup {
+            let _ = thread.join();
+            return Err(e);
+        }
+        Ok(NodeHandle {
+            commands: commands_tx,
+            thread: Some(thread),
+        })
+    }
+}
 
-        Self {
-            id,
-            socket,
+impl<T: Transport> Node<T> {
+    // Runs the node over `transport` instead of a UDP socket bound to the configured address
+    pub fn with_transport(config: NodeConfig, transport: T) -> Self {
+        Self::with_clock(config, transport, Arc::new(SystemClock))
+    }
+
+    // Takes time from `clock` for timers, blocks and transactions, so the node can run
+    // on virtual time
+    pub fn with_clock(config: NodeConfig, transport: T, clock: Arc<dyn Clock>) -> Self {
+        Self::try_with_clock(config, transport, clock).expect("Failed to start node")
+    }
+
+    // Like `with_clock`, but returns an error when the config is invalid or the key,
+    // web server, block store or cache cannot be set up
+    pub fn try_with_clock(
+        config: NodeConfig,
+        transport: T,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Self> {
+        config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let (key_pair, generated_key) = match &config.key_path {
+            Some(key_path) => {
+                keys::load_or_generate(key_path).map_err(context("Failed to load node key"))?
+            }
+            None => (keys::ephemeral(), false),
+        };
+        let (web_server, rx) = if config.web {
+            let (web_server, rx) = WebServer::new(&config.web_address(), &config.web_root)
+                .map_err(context("Failed to start web server"))?;
+            (Some(web_server), rx)
+        } else {
+            (None, channel().1)
+        };
+        let election = Election::new(
+            config.heartbeat_period,
+            config.election_timeout,
+            config.candidate,
+            clock.clone(),
+        );
+        let (store, discarded_bytes): (Box<dyn BlockStore>, u64) = match &config.data_dir {
+            Some(data_dir) => {
+                let store = FileBlockStore::open(data_dir)
+                    .map_err(context("Failed to open block store"))?;
+                let discarded_bytes = store.discarded_bytes();
+                (Box::new(store), discarded_bytes)
+            }
+            None => (Box::new(InMemoryBlockStore::new()), 0),
+        };
+        let chain = store.blocks().map_err(context("Failed to load blocks"))?;
+        let sealed_transactions = chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(|txn| txn.hash)
+            .collect();
+        let discovery = config.discovery_group.as_deref().map(Discovery::join);
+        let mut discarded_records = 0;
+        let mut missing_values = Vec::new();
+        let cache: Box<dyn Cache> = match &config.cache_dir {
+            Some(cache_dir) => {
+                let mut cache =
+                    DurableCache::open(cache_dir).map_err(context("Failed to open cache"))?;
+                discarded_records = cache.discarded_records();
+                missing_values = cache
+                    .rebuild(&chain)
+                    .map_err(context("Failed to rebuild cache"))?;
+                Box::new(cache)
+            }
+            None => Box::new(InMemoryCache::new()),
+        };
+
+        let mut node = Self {
+            id: config.id,
+            config,
+            transport,
             addr_table: HashMap::new(),
+            client_addrs: HashMap::new(),
+            client_keys: HashMap::new(),
             pending_acks: HashMap::new(),
+            held_packets: HashMap::new(),
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT, clock.clone()),
+            replay_filter: ReplayFilter::new(REPLAY_WINDOW, MAX_PACKET_AGE, clock.clone()),
+            sequence: initial_sequence(),
+            dropped_packets: 0,
+            failed_sends: 0,
+            rejected_peers: 0,
             web_server,
             web_signal_rx: rx,
-            cache: InMemoryCache::new(),
-            leader: 0,
-            key_pair: Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap(),
+            cache,
+            election,
+            sessions: HashMap::new(),
+            handshakes: HashMap::new(),
+            key_pair,
             peer_public_keys: HashMap::new(),
+            peers: HashMap::new(),
+            gossiped_keys: HashMap::new(),
+            last_ping: 0,
+            last_gossip: 0,
+            discovery: None,
             pending_transactions: HashMap::new(),
-            chain: Vec::new(),
+            sealed_transactions,
+            latest_writes: HashMap::new(),
+            seen_requests: HashMap::new(),
+            chain,
+            fork_chain: Vec::new(),
+            committed_height: 0,
+            match_heights: HashMap::new(),
+            store,
+            clock,
+        };
+        node.rebuild_latest_writes();
+        if generated_key {
+            node.system_log(format!(
+                "Generated node key {:?}",
+                node.config.key_path.as_deref().unwrap_or_default()
+            ));
+        }
+        node.system_log(format!(
+            "Public key {}",
+            hex_string(node.key_pair.public_key().as_ref())
+        ));
+        if discarded_bytes > 0 {
+            node.system_log(format!(
+                "Recovered block store: discarded {} bytes of torn or corrupt records",
+                discarded_bytes
+            ));
+        }
+        if !node.chain.is_empty() {
+            node.system_log(format!("Loaded {} blocks from disk", node.chain.len()));
+        }
+        if discarded_records > 0 {
+            node.system_log(format!(
+                "Recovered cache: discarded {} torn or corrupt log records",
+                discarded_records
+            ));
+        }
+        match discovery {
+            Some(Ok(discovery)) => {
+                node.system_log(format!(
+                    "Discovering peers on {}{}",
+                    node.config.discovery_group.as_deref().unwrap_or_default(),
+                    if discovery.is_listening() {
+                        ""
+                    } else {
+                        " (announcing only, group port in use)"
+                    }
+                ));
+                node.discovery = Some(discovery);
+            }
+            Some(Err(e)) => node.system_log(format!("Multicast discovery disabled: {}", e)),
+            None => {}
+        }
+        if !missing_values.is_empty() {
+            node.system_log(format!(
+                "Cache has no stored value for {:?} recorded on the chain",
+                missing_values
+            ));
+        }
+        Ok(node)
+    }
+
+    // Draws election timeouts from `seed` instead of the system random source
+    pub fn election_seed(mut self, seed: u64) -> Self {
+        self.election = self.election.seeded(seed);
+        self
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn chain(&self) -> &[Block] {
+        &self.chain
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.election.is_leader()
+    }
+
+    pub fn peer(&self, id: u16) -> Option<&Peer> {
+        self.peers.get(&id)
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        NodeStatus {
+            id: self.id,
+            role: self.election.role,
+            term: self.election.term,
+            leader: self.election.leader,
+            chain_height: self.chain.len(),
+            pending_transactions: self.pending_transactions.len(),
+            peers: self.peers.len(),
+            dropped_packets: self.dropped_packets,
+            failed_sends: self.failed_sends,
         }
     }
 
     pub fn run(&mut self) {
-        if self.id != self.leader {
-            self.send_probe(&format!("127.0.0.1:{}", ATLAS_PORT));
+        self.start();
+        loop {
+            self.tick();
+            self.poll(Duration::from_millis(self.config.read_timeout));
         }
-        let mut buffer = [0; PACKET_BUFFER_SIZE];
-        self.web_server.run();
+    }
+
+    // Runs until a Shutdown command arrives or the handle is dropped
+    fn serve(&mut self, commands: Receiver<NodeCommand>) {
+        self.start();
         loop {
-            self.check_ack_timeouts();
+            match commands.try_recv() {
+                Ok(NodeCommand::Status(reply)) => {
+                    let _ = reply.send(self.status());
+                }
+                Ok(NodeCommand::Shutdown) | Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Empty) => {}
+            }
+            self.tick();
+            self.poll(Duration::from_millis(self.config.read_timeout));
+        }
+        self.shutdown();
+    }
+
+    // Leaves the network: a leader seals its pending transactions into a final block,
+    // peers are told the node is leaving and given one ack timeout to acknowledge,
+    // then the web server is stopped
+    pub fn shutdown(&mut self) {
+        self.system_log("Shutting down".to_string());
+        if self.election.is_leader() && !self.pending_transactions.is_empty() {
+            self.create_block();
+        }
+        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
+        for peer in peers {
+            let leave_packet = Packet::new(self.id, peer, PacketType::Leave, vec![]);
+            self.send(&leave_packet);
+        }
+        // Stops early once a wait passes with nothing arriving, which is immediate on
+        // virtual time
+        let deadline = self.clock.now() + self.config.ack_timeout;
+        while !self.pending_acks.is_empty()
+            && self.clock.now() < deadline
+            && self.poll(Duration::from_millis(self.config.read_timeout))
+        {}
+        if let Some(web_server) = &self.web_server {
+            web_server.shutdown();
+        }
+    }
 
-            if let Ok(signal) = self.web_signal_rx.try_recv() {
-                self.handle_web_signal(signal);
+    // Probes the seed peers and, when configured as leader, takes leadership. Called
+    // once before the first `tick`
+    pub fn start(&mut self) {
+        for seed in self.config.seed_peers.clone() {
+            if seed.id != self.id {
+                self.send_probe(seed.id, &seed.address);
             }
+        }
+        if self.config.leader == Some(self.id) {
+            self.election.start_election(self.id);
+            self.become_leader();
+        }
+        if let Some(web_server) = &self.web_server {
+            web_server.run();
+        }
+    }
+
+    // Runs everything driven by time rather than by packets: retransmissions,
+    // expiring fragments, elections, session rotation and block creation
+    pub fn tick(&mut self) {
+        self.check_ack_timeouts();
+
+        let expired = self.reassembler.expire();
+        if expired > 0 {
+            self.network_log(format!("Dropped {} incomplete fragmented packets", expired));
+        }
+
+        if let Ok(signal) = self.web_signal_rx.try_recv() {
+            self.handle_web_signal(signal);
+        }
+
+        self.check_election();
+        self.check_sessions();
+        self.check_peers();
+        self.check_gossip();
 
-            if self.id == self.leader {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
+        if self.election.is_leader() {
+            let now = self.clock.now_secs();
+
+            if self.chain.is_empty()
+                || now.saturating_sub(self.chain.last().unwrap().timestamp)
+                    >= self.config.block_period
+            {
+                self.create_block();
+            }
+        }
+    }
 
-                if self.chain.is_empty() {
-                    self.create_block();
-                } else if now - self.chain.last().unwrap().timestamp >= BLOCK_PERIOD {
-                    self.create_block();
+    // Waits up to `timeout` for one packet and handles it. Returns false if nothing
+    // arrived
+    pub fn poll(&mut self, timeout: Duration) -> bool {
+        let mut buffer = [0; PACKET_BUFFER_SIZE];
+        let Ok((size, addr)) = self.transport.recv_from(&mut buffer, timeout) else {
+            return false;
+        };
+        let mut packet = match Packet::from_bytes(&buffer[..size]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                self.drop_packet(&addr, e);
+                return true;
+            }
+        };
+        let authenticated = match self.authenticate(&packet) {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                self.drop_packet(&addr, e);
+                return true;
+            }
+        };
+        let encrypted = packet.encrypted;
+        if encrypted
+            && !self
+                .sessions
+                .get(&packet.src)
+                .is_some_and(|session| session.open(&mut packet))
+        {
+            self.drop_packet(&addr, ProtocolError::DecryptionFailed(packet.src));
+            return true;
+        }
+        // Clients talk over their session, except for the handshake opening it and acks
+        if !authenticated
+            && !encrypted
+            && !matches!(packet.packet_type, PacketType::Ack | PacketType::Handshake)
+        {
+            self.drop_packet(
+                &addr,
+                ProtocolError::Unauthenticated {
+                    src: packet.src,
+                    packet_type: packet.packet_type,
+                },
+            );
+            return true;
+        }
+        // Peers exchange data only over their session
+        if authenticated && !encrypted {
+            match packet.carried_type() {
+                Ok(packet_type) if packet_type.carries_data() => {
+                    self.drop_packet(
+                        &addr,
+                        ProtocolError::Unencrypted {
+                            src: packet.src,
+                            packet_type,
+                        },
+                    );
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.drop_packet(&addr, e);
+                    return true;
                 }
             }
+        }
+        let replay = self.replay_filter.check(&packet);
+        if replay == Replay::Stale {
+            self.drop_packet(
+                &addr,
+                ProtocolError::Replayed {
+                    src: packet.src,
+                    sequence: packet.sequence,
+                },
+            );
+            return true;
+        }
+        // A new node's address is only learned once its Probe or Sync admits it, and a
+        // client's once its handshake is verified or it sends over its session
+        let admitted = authenticated && self.peer_public_keys.contains_key(&packet.src);
+        // Packets signed with a pinned key or sealed with a session key are recorded
+        // now. Probes, Syncs and client handshakes wait until they are accepted, and
+        // unsigned acks are never recorded
+        let trusted = admitted || encrypted;
+        if trusted && replay == Replay::Fresh {
+            self.replay_filter.record(packet.src, packet.sequence);
+        }
+        if admitted {
+            self.addr_table.insert(packet.src, addr.clone());
+        } else if !authenticated && encrypted {
+            self.client_addrs.insert(packet.src, addr.clone());
+        }
+        let known = admitted || self.client_addrs.get(&packet.src) == Some(&addr);
+        self.network_log(format!(
+            "Received {:?}-0x{:X} from {:?}",
+            packet.packet_type, packet.packet_id, packet.src
+        ));
+        if packet.packet_type != PacketType::Ack && known {
+            self.reply_ack(&packet);
+        }
+        if replay == Replay::Duplicate {
+            // Acked again so the sender stops retransmitting, but not processed twice
+            self.network_log(format!(
+                "Ignored duplicate {:?}-0x{:X} from {:?}",
+                packet.packet_type, packet.packet_id, packet.src
+            ));
+            return true;
+        }
+        let src = packet.src;
+        let packet_id = packet.packet_id;
+        let sequence = packet.sequence;
+        let packet_type = packet.packet_type;
+        let acked = packet.packet_type == PacketType::Ack || known;
+        let accepted = match self.dispatch(packet, authenticated, &addr) {
+            Ok(()) => true,
+            Err(e) => {
+                self.drop_packet(&addr, e);
+                false
+            }
+        };
+        let known = if authenticated {
+            self.peer_public_keys.contains_key(&src)
+        } else {
+            self.client_addrs.get(&src) == Some(&addr)
+        };
+        // A Probe or Sync that admitted its sender, or a client handshake that verified
+        if !trusted
+            && accepted
+            && match packet_type {
+                PacketType::Probe | PacketType::Sync => authenticated && known,
+                PacketType::Handshake => !authenticated,
+                _ => false,
+            }
+        {
+            self.replay_filter.record(src, sequence);
+        }
+        if !acked && known {
+            // The Probe, Sync or client Handshake that admitted the sender, acked now
+            // its address is known
+            let ack_packet = Packet::new(
+                self.id,
+                src,
+                PacketType::Ack,
+                AckPayload::new(packet_id).as_bytes(),
+            );
+            self.send(&ack_packet);
+        }
+        // Checked after dispatch so a Probe or Sync that admits the peer counts
+        if authenticated && self.peer_public_keys.contains_key(&src) {
+            self.peer_seen(src);
+        }
+        // Released only now, after the Sync or Handshake reply that gives the peer
+        // the session key
+        self.release_held(src);
+        true
+    }
 
-            let Ok((size, addr)) = self.socket.recv_from(&mut buffer) else {
-                continue;
-            };
-            let Some(packet) = Packet::from_bytes(&buffer[..size]) else {
-                continue;
+    // Checks the packet signature against the sender's known key, or the key it
+    // presents in a Probe or Sync. A key pinned on first contact is never replaced, and
+    // a Sync answering a gossiped probe must carry the gossiped key. Returns false for
+    // unsigned packets from senders that are not peers, such as clients
+    fn authenticate(&self, packet: &Packet) -> Result<bool, ProtocolError> {
+        let presented = match packet.packet_type {
+            PacketType::Probe => Some(ProbePayload::from_bytes(&packet.payload)?.public_key),
+            PacketType::Sync => Some(SyncPayload::from_bytes(&packet.payload)?.public_key),
+            _ => None,
+        };
+        let expected = match self.peer_public_keys.get(&packet.src) {
+            Some(pinned) => Some(pinned.as_slice()),
+            None if packet.packet_type == PacketType::Sync => self
+                .gossiped_keys
+                .get(&packet.src)
+                .map(|key| key.as_slice()),
+            None => None,
+        };
+        let public_key = match (presented, expected) {
+            (Some(presented), Some(expected)) if presented[..] != *expected => {
+                return Err(ProtocolError::KeyMismatch(packet.src));
+            }
+            (Some(presented), _) => Some(presented.to_vec()),
+            (None, expected) => expected.map(<[u8]>::to_vec),
+        };
+        match public_key {
+            Some(public_key) if packet.verify_signature(&public_key) => Ok(true),
+            Some(_) => Err(ProtocolError::InvalidSignature(packet.src)),
+            None => Ok(false),
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        packet: Packet,
+        authenticated: bool,
+        addr: &str,
+    ) -> Result<(), ProtocolError> {
+        let packet = if packet.packet_type == PacketType::Fragment {
+            let Some(packet) = self.reassembler.insert(&packet)? else {
+                return Ok(());
             };
-            self.addr_table.insert(packet.src, addr.to_string());
             self.network_log(format!(
-                "Received {:?}-0x{:X} from {:?}",
-                PacketType::from(packet.packet_type),
+                "Reassembled {:?}-0x{:X} ({} bytes) from {:?}",
+                packet.packet_type,
                 packet.packet_id,
+                packet.payload.len(),
                 packet.src
             ));
-            if PacketType::from(packet.packet_type) != PacketType::Ack {
-                self.reply_ack(&packet);
-            }
-            match PacketType::from(packet.packet_type) {
-                PacketType::Ack => self.handle_ack(&packet),
-                PacketType::Probe => self.handle_probe(packet),
-                PacketType::Sync => self.handle_sync(&packet),
-                PacketType::SetData => self.handle_set_data(&packet),
-                PacketType::GetData => self.handle_get_data(&packet),
-                PacketType::GetChain => self.handle_get_chain(&packet),
-                PacketType::Chain => self.handle_chain(&packet),
-                PacketType::Transaction => self.handle_transaction(&packet),
-                PacketType::Block => self.handle_block(&packet),
-                _ => (),
-            }
+            packet
+        } else {
+            packet
+        };
+        // Only client requests are accepted without a peer signature
+        if !authenticated
+            && !matches!(
+                packet.packet_type,
+                PacketType::Ack
+                    | PacketType::SetData
+                    | PacketType::GetData
+                    | PacketType::DeleteData
+                    | PacketType::GetProof
+                    | PacketType::Handshake
+            )
+        {
+            return Err(ProtocolError::Unauthenticated {
+                src: packet.src,
+                packet_type: packet.packet_type,
+            });
+        }
+        match packet.packet_type {
+            PacketType::Ack => self.handle_ack(&packet),
+            PacketType::Probe => self.handle_probe(packet, addr),
+            PacketType::Sync => self.handle_sync(&packet, addr),
+            PacketType::SetData => self.handle_set_data(&packet),
+            PacketType::GetData => self.handle_get_data(&packet),
+            PacketType::DeleteData => self.handle_delete_data(&packet),
+            PacketType::GetChain => self.handle_get_chain(&packet),
+            PacketType::Chain => self.handle_chain(&packet),
+            PacketType::Transaction => self.handle_transaction(&packet),
+            PacketType::Block => self.handle_block(&packet),
+            PacketType::Replicate => self.handle_replicate(&packet),
+            PacketType::ReplicateDelete => self.handle_replicate_delete(&packet),
+            PacketType::GetProof => self.handle_get_proof(&packet),
+            PacketType::Heartbeat => self.handle_heartbeat(&packet),
+            PacketType::RequestVote => self.handle_request_vote(&packet),
+            PacketType::Vote => self.handle_vote(&packet),
+            PacketType::Handshake => self.handle_handshake(&packet, authenticated, addr),
+            PacketType::Leave => self.handle_leave(&packet),
+            // Answered by the ack alone
+            PacketType::Ping => Ok(()),
+            PacketType::PeerList => self.handle_peer_list(&packet),
+            _ => Ok(()),
         }
     }
 
+    fn drop_packet(&mut self, addr: &str, error: ProtocolError) {
+        self.dropped_packets += 1;
+        self.network_log(format!(
+            "Dropped packet from {}: {} ({} dropped)",
+            addr, error, self.dropped_packets
+        ));
+    }
+
     fn handle_web_signal(&mut self, signal: WebSignal) {
         match signal {
             WebSignal::GetChain { client_id } => {
@@ -133,7 +779,7 @@ impl Node {
                     })
                     .collect();
 
-                self.web_server.send_to_client(
+                self.send_to_web_client(
                     client_id,
                     serde_json::json!({
                         "type": "chain",
@@ -144,22 +790,14 @@ impl Node {
                 );
             }
             WebSignal::GetCache { client_id } => {
-                self.web_server.send_to_client(
-                    client_id,
-                    serde_json::json!({
-                        "type": "cache",
-                        "value": self.cache.metadata()
-                    })
-                    .to_string()
-                    .as_bytes(),
-                );
+                self.send_to_web_client(client_id, self.cache_message().as_bytes());
             }
             WebSignal::GetPeers { client_id } => {
-                self.web_server.send_to_client(
+                self.send_to_web_client(
                     client_id,
                     serde_json::json!({
                         "type": "peers",
-                        "value": self.addr_table.values().cloned().collect::<Vec<String>>()
+                        "value": self.peer_list()
                     })
                     .to_string()
                     .as_bytes(),
@@ -177,7 +815,7 @@ impl Node {
                         }
                     }
                 }
-                self.web_server.send_to_client(
+                self.send_to_web_client(
                     client_id,
                     serde_json::json!({
                         "type": "history",
@@ -187,246 +825,1690 @@ impl Node {
                     .as_bytes(),
                 );
             }
+            WebSignal::GetProof {
+                client_id,
+                transaction_hash,
+            } => {
+                let proof = parse_hex(&transaction_hash)
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+                    .and_then(|hash| self.find_proof(&hash));
+                let value = match proof {
+                    Some(proof_payload) => serde_json::json!({
+                        "block_height": proof_payload.block_height,
+                        "merkle_root": hex_string(&proof_payload.merkle_root),
+                        "proof": proof_payload.proof,
+                    }),
+                    None => serde_json::Value::Null,
+                };
+                self.send_to_web_client(
+                    client_id,
+                    serde_json::json!({
+                        "type": "proof",
+                        "value": value
+                    })
+                    .to_string()
+                    .as_bytes(),
+                );
+            }
+            WebSignal::DeleteData { data_name } => self.delete_from_web(data_name),
+        }
+    }
+
+    fn find_proof(&self, transaction_hash: &[u8; 32]) -> Option<ProofPayload> {
+        self.chain.iter().enumerate().find_map(|(height, block)| {
+            block
+                .merkle_proof(transaction_hash)
+                .map(|proof| ProofPayload::new(height as u32, block.merkle_root, proof))
+        })
+    }
+
+    // This node followed by every peer node with its liveness, latency and public key
+    fn peer_list(&self) -> Vec<serde_json::Value> {
+        let mut peers = vec![serde_json::json!({
+            "id": self.id,
+            "address": self.config.atlas_address(),
+            "public_key": hex_string(self.key_pair.public_key().as_ref()),
+            "state": "self",
+            "last_seen": null,
+            "rtt": null,
+        })];
+        let mut ids: Vec<&u16> = self.peers.keys().collect();
+        ids.sort();
+        for id in ids {
+            let peer = &self.peers[id];
+            peers.push(serde_json::json!({
+                "id": id,
+                "address": self.addr_table.get(id),
+                "public_key": self.peer_public_keys.get(id).map(|key| hex_string(key)),
+                "state": peer.state,
+                "last_seen": peer.last_seen,
+                "rtt": peer.rtt,
+            }));
+        }
+        peers
+    }
+
+    fn broadcast_message(&self, message: &[u8]) {
+        if let Some(web_server) = &self.web_server {
+            web_server.broadcast_message(message);
+        }
+    }
+
+    fn send_to_web_client(&self, client_id: usize, message: &[u8]) {
+        if let Some(web_server) = &self.web_server {
+            web_server.send_to_client(client_id, message);
+        }
+    }
+
+    fn broadcast_peers(&self) {
+        self.broadcast_message(
+            serde_json::json!({
+                "type": "peers",
+                "value": self.peer_list()
+            })
+            .to_string()
+            .as_bytes(),
+        );
+    }
+
+    fn peer_seen(&mut self, id: u16) {
+        let now = self.clock.now();
+        match self.peers.get_mut(&id) {
+            Some(peer) => {
+                if peer.seen(now) {
+                    self.system_log(format!("Peer {} is alive again", id));
+                    self.broadcast_peers();
+                }
+            }
+            None => {
+                self.peers.insert(id, Peer::new(now));
+                self.broadcast_peers();
+                // Introduces the newcomer to everyone else and everyone else to it
+                self.gossip_peers();
+            }
+        }
+    }
+
+    // Pings every peer once per heartbeat period and moves peers that have gone
+    // quiet to suspect or dead. Packets still waiting on a dead peer are dropped
+    fn check_peers(&mut self) {
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_ping) >= self.config.heartbeat_period {
+            self.last_ping = now;
+            let peers: Vec<u16> = self.peers.keys().copied().collect();
+            for peer in peers {
+                let ping_packet = Packet::new(self.id, peer, PacketType::Ping, vec![]);
+                self.send(&ping_packet);
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (id, peer) in &mut self.peers {
+            if let Some(state) =
+                peer.update(now, self.config.suspect_timeout, self.config.dead_timeout)
+            {
+                changes.push((*id, state));
+            }
+        }
+        for (id, state) in &changes {
+            self.system_log(format!(
+                "Peer {} is {} after {} ms of silence",
+                id,
+                state,
+                now.saturating_sub(self.peers[id].last_seen)
+            ));
+            if *state == PeerState::Dead {
+                self.pending_acks
+                    .retain(|_, (_, _, pending)| pending.dst != *id);
+            }
+        }
+        if !changes.is_empty() {
+            self.broadcast_peers();
+        }
+    }
+
+    // Gossips known peers and announces this node on the discovery group once per
+    // gossip period, then probes any node announced since the last tick
+    fn check_gossip(&mut self) {
+        let now = self.clock.now();
+        if now.saturating_sub(self.last_gossip) >= self.config.gossip_period {
+            self.last_gossip = now;
+            self.gossip_peers();
+            self.announce();
+        }
+        self.receive_announcements();
+    }
+
+    // Live peers with a known key and address, other than `exclude`
+    fn peer_entries(&self, exclude: u16) -> Vec<PeerEntry> {
+        let mut entries: Vec<PeerEntry> = self
+            .peers
+            .iter()
+            .filter(|(id, peer)| **id != exclude && peer.state != PeerState::Dead)
+            .filter_map(|(id, _)| {
+                Some(PeerEntry {
+                    id: *id,
+                    public_key: self.peer_public_keys.get(id)?.as_slice().try_into().ok()?,
+                    address: self.addr_table.get(id)?.clone(),
+                })
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+
+    // Sends every peer the other peers this node knows, so the mesh closes from any seed
+    fn gossip_peers(&mut self) {
+        let peers: Vec<u16> = self.peers.keys().copied().collect();
+        for peer in peers {
+            let entries = self.peer_entries(peer);
+            if entries.is_empty() {
+                continue;
+            }
+            let peer_list_packet = Packet::new(
+                self.id,
+                peer,
+                PacketType::PeerList,
+                PeerListPayload::new(entries).as_bytes(),
+            );
+            self.send(&peer_list_packet);
+        }
+    }
+
+    fn announce(&mut self) {
+        let Some(discovery) = &self.discovery else {
+            return;
+        };
+        let mut announcement = Packet::new(
+            self.id,
+            0,
+            PacketType::PeerList,
+            PeerListPayload::new(vec![PeerEntry {
+                id: self.id,
+                public_key: self.key_pair.public_key().as_ref().try_into().unwrap(),
+                address: self.config.atlas_address(),
+            }])
+            .as_bytes(),
+        );
+        announcement.timestamp = self.clock.now();
+        announcement.signature = self
+            .sign(announcement.signing_bytes())
+            .as_ref()
+            .try_into()
+            .unwrap();
+        if let Err(e) = discovery.announce(&announcement.as_bytes()) {
+            self.network_log(format!("Failed to announce on discovery group: {}", e));
+        }
+    }
+
+    // Probes nodes announced on the discovery group. An announcement carries the
+    // sender as its only entry and is signed with the key in it
+    fn receive_announcements(&mut self) {
+        let mut buffer = [0; PACKET_BUFFER_SIZE];
+        loop {
+            let Some((size, from)) = self
+                .discovery
+                .as_ref()
+                .and_then(|discovery| discovery.receive(&mut buffer))
+            else {
+                return;
+            };
+            let Ok(announcement) = Packet::from_bytes(&buffer[..size]) else {
+                continue;
+            };
+            if announcement.packet_type != PacketType::PeerList || announcement.src == self.id {
+                continue;
+            }
+            let Ok(PeerListPayload { peers }) = PeerListPayload::from_bytes(&announcement.payload)
+            else {
+                continue;
+            };
+            let [entry] = peers.as_slice() else {
+                continue;
+            };
+            if entry.id != announcement.src || !announcement.verify_signature(&entry.public_key) {
+                continue;
+            }
+            // A node bound to every interface announces 0.0.0.0, reachable at the
+            // address the announcement came from
+            let address = match entry.address.parse::<SocketAddr>() {
+                Ok(address) if address.ip().is_unspecified() => {
+                    SocketAddr::new(from.ip(), address.port()).to_string()
+                }
+                _ => entry.address.clone(),
+            };
+            self.discover(entry, &address, &format!("multicast from {}", from));
+        }
+    }
+
+    // Probes a node learned of through gossip or multicast, unless it is this node,
+    // already connected, being probed, or not an admitted member. The key it was
+    // announced with is remembered so its Sync can be checked against it
+    fn discover(&mut self, entry: &PeerEntry, address: &str, via: &str) {
+        if entry.id == self.id
+            || self.peers.contains_key(&entry.id)
+            || self.handshake_pending(entry.id)
+            || !self.config.is_member(entry.id, &entry.public_key)
+        {
+            return;
+        }
+        let known_key = self
+            .peer_public_keys
+            .get(&entry.id)
+            .map(Vec::as_slice)
+            .or_else(|| self.gossiped_keys.get(&entry.id).map(|key| key.as_slice()));
+        if known_key.is_some_and(|key| key != entry.public_key.as_slice()) {
+            self.system_log(format!(
+                "Ignored node {} from {}: key {} does not match the one already known",
+                entry.id,
+                via,
+                hex_string(&entry.public_key)
+            ));
+            return;
+        }
+        // Gossiped addresses come from other hosts, so only well-formed ones are tried
+        let Ok(address) = address.parse::<SocketAddr>() else {
+            self.system_log(format!(
+                "Ignored node {} from {}: {:?} is not a socket address",
+                entry.id, via, address
+            ));
+            return;
+        };
+        self.gossiped_keys.insert(entry.id, entry.public_key);
+        self.system_log(format!(
+            "Discovered node {} at {} via {}",
+            entry.id, address, via
+        ));
+        self.send_probe(entry.id, &address.to_string());
+    }
+
+    fn sign(&self, message: Vec<u8>) -> Signature {
+        self.key_pair.sign(message.as_ref())
+    }
+
+    fn known_public_keys(&self) -> HashMap<u16, Vec<u8>> {
+        let mut public_keys = self.peer_public_keys.clone();
+        public_keys.insert(self.id, self.key_pair.public_key().as_ref().to_vec());
+        public_keys
+    }
+
+    // Validates `blocks` as the continuation of a chain whose last block hash is
+    // `prev_block_hash` and which is `start_height` blocks long
+    fn validate_blocks(
+        &self,
+        start_height: usize,
+        mut prev_block_hash: [u8; 32],
+        blocks: &[Block],
+    ) -> Result<(), (usize, ValidationError)> {
+        let public_keys = self.known_public_keys();
+        for (i, block) in blocks.iter().enumerate() {
+            block
+                .validate(&prev_block_hash, &public_keys)
+                .map_err(|e| (start_height + i, e))?;
+            prev_block_hash = block.merkle_root;
+        }
+        Ok(())
+    }
+
+    // Persists `block` before adding it to the in-memory chain. A block that could not
+    // be stored is not applied, so the chain never runs ahead of the store. Its
+    // transactions are no longer pending, however the block arrived
+    fn append_block(&mut self, block: Block) -> io::Result<()> {
+        self.store.append(&block)?;
+        for txn in &block.transactions {
+            Self::record_write(&mut self.latest_writes, txn);
+            self.sealed_transactions.insert(txn.hash);
+            if self.pending_transactions.remove(&txn.hash).is_some() {
+                self.system_log(format!(
+                    "Removed transaction {} from pending transactions",
+                    hex_string(&txn.hash)
+                ));
+            }
+        }
+        self.chain.push(block);
+        Ok(())
+    }
+
+    // Appends blocks in order, stopping at the first that fails to persist
+    fn append_blocks(&mut self, blocks: Vec<Block>) -> io::Result<()> {
+        blocks
+            .into_iter()
+            .try_for_each(|block| self.append_block(block))
+    }
+
+    // Cached entries for the dashboard, along with the names that were deleted
+    fn cache_message(&self) -> String {
+        serde_json::json!({
+            "type": "cache",
+            "value": self.cache.metadata(),
+            "tombstones": self.tombstones()
+        })
+        .to_string()
+    }
+
+    // Names whose latest write, on the chain or pending, is a delete
+    fn tombstones(&self) -> Vec<serde_json::Value> {
+        self.latest_writes
+            .iter()
+            .filter(|(name, (operation, _, _))| {
+                *operation == CacheOperation::Delete && self.cache.value_hash(name).is_none()
+            })
+            .map(|(name, (_, timestamp, _))| {
+                serde_json::json!({
+                    "name": name,
+                    "deleted_at": timestamp
+                })
+            })
+            .collect()
+    }
+
+    fn tip_hash(chain: &[Block]) -> [u8; 32] {
+        chain.last().map_or([0; 32], |block| block.merkle_root)
+    }
+
+    // Notes `txn` in `latest_writes` if it is a write or delete at least as new as the
+    // one held
+    fn record_write(
+        latest_writes: &mut HashMap<String, (CacheOperation, u64, [u8; 32])>,
+        txn: &Transaction,
+    ) {
+        if txn.denied || txn.operation == CacheOperation::Get {
+            return;
+        }
+        Self::record_version(
+            latest_writes,
+            &txn.data_name,
+            txn.operation,
+            txn.timestamp,
+            txn.hash,
+        );
+    }
+
+    fn record_version(
+        latest_writes: &mut HashMap<String, (CacheOperation, u64, [u8; 32])>,
+        name: &str,
+        operation: CacheOperation,
+        timestamp: u64,
+        transaction_hash: [u8; 32],
+    ) {
+        if Self::is_latest(latest_writes, name, timestamp, transaction_hash) {
+            latest_writes.insert(name.to_string(), (operation, timestamp, transaction_hash));
+        }
+    }
+
+    // Whether a write of `name` is at least as new as the latest one held. Writes are
+    // ordered by timestamp, then by transaction hash, so every node settles on the
+    // same one. The latest write itself counts, as it may arrive more than once
+    fn is_latest(
+        latest_writes: &HashMap<String, (CacheOperation, u64, [u8; 32])>,
+        name: &str,
+        timestamp: u64,
+        transaction_hash: [u8; 32],
+    ) -> bool {
+        latest_writes
+            .get(name)
+            .is_none_or(|(_, held_timestamp, held_hash)| {
+                (*held_timestamp, *held_hash) <= (timestamp, transaction_hash)
+            })
+    }
+
+    // Recomputes `latest_writes` from the whole chain and the pending transactions, for
+    // when blocks are removed rather than appended
+    fn rebuild_latest_writes(&mut self) {
+        self.latest_writes.clear();
+        let transactions = self
+            .chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .chain(self.pending_transactions.values());
+        for txn in transactions {
+            Self::record_write(&mut self.latest_writes, txn);
+        }
+    }
+
+    // Signs `txn` as this node, queues it for the next block and shares it with peers
+    fn create_transaction(&mut self, mut txn: Transaction) {
+        self.system_log(format!(
+            "Created transaction: client {} {:?}{} data {:?} at node {} on {}",
+            txn.client_id,
+            txn.operation,
+            if txn.denied { " (denied)" } else { "" },
+            txn.data_name,
+            self.id,
+            txn.timestamp
+        ));
+
+        txn.signature = self.sign(txn.signing_bytes()).as_ref().try_into().unwrap();
+        let txn_payload = TransactionPayload::new(txn.clone());
+
+        self.pending_transactions.insert(txn.hash, txn.clone());
+        Self::record_write(&mut self.latest_writes, &txn);
+
+        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
+        for peer in peers {
+            let txn_packet = Packet::new(
+                self.id,
+                peer,
+                PacketType::Transaction,
+                txn_payload.as_bytes(),
+            );
+
+            self.send(&txn_packet);
+        }
+
+        self.broadcast_message(
+            serde_json::json!({
+                "type": "transaction",
+                "value": txn
+            })
+            .to_string()
+            .as_bytes(),
+        );
+    }
+
+    fn create_block(&mut self) {
+        let transactions = self.pending_transactions.values().cloned().collect();
+        self.pending_transactions.clear();
+        let mut block = Block::new(
+            self.clock.as_ref(),
+            transactions,
+            if self.chain.is_empty() {
+                [0; 32]
+            } else {
+                self.chain.last().unwrap().merkle_root
+            },
+            self.id,
+        );
+        block.signature = self.sign(block.signing_bytes()).as_ref().try_into().unwrap();
+
+        let block_payload = BlockPayload::new(block.clone());
+        self.system_log(format!(
+            "Created block #{:?} {}",
+            self.chain.len(),
+            hex_string(&block.merkle_root)
+        ));
+        if let Err(e) = self.append_block(block.clone()) {
+            self.system_log(format!(
+                "Failed to persist block #{} {}: {}",
+                self.chain.len(),
+                hex_string(&block.merkle_root),
+                e
+            ));
+            // Kept for the next block
+            for txn in block.transactions {
+                self.pending_transactions.insert(txn.hash, txn);
+            }
+            return;
+        }
+        self.advance_commit();
+
+        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
+        for peer in peers {
+            let block_packet =
+                Packet::new(self.id, peer, PacketType::Block, block_payload.as_bytes());
+            self.send(&block_packet);
+        }
+
+        self.broadcast_message(
+            serde_json::json!({
+                "type": "block",
+                "value": {
+                    "timestamp": block.timestamp,
+                    "merkle_root": hex_string(&block.merkle_root),
+                    "transaction_count": block.transactions.len()
+                }
+            })
+            .to_string()
+            .as_bytes(),
+        );
+    }
+
+    // Nodes a majority is counted over: the configured members, or with open membership
+    // this node, its seed peers and every peer met since. A node that is isolated or
+    // just started can then not elect itself alone
+    fn cluster_size(&self) -> usize {
+        let mut nodes: HashSet<u16> = self.peer_public_keys.keys().copied().collect();
+        nodes.insert(self.id);
+        if self.config.members.is_empty() {
+            nodes.extend(self.config.seed_peers.iter().map(|seed| seed.id));
+        } else {
+            nodes.extend(self.config.members.iter().map(|member| member.id));
+        }
+        nodes.len()
+    }
+
+    fn check_election(&mut self) {
+        if self.election.heartbeat_due() {
+            self.send_heartbeats();
+        }
+        if self.election.election_due() {
+            self.start_election();
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.election.start_election(self.id);
+        self.system_log(format!("Started election for term {}", self.election.term));
+        if self.election.has_majority(self.cluster_size()) {
+            self.become_leader();
+            return;
+        }
+
+        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
+        for peer in peers {
+            let request_vote_packet = Packet::new(
+                self.id,
+                peer,
+                PacketType::RequestVote,
+                RequestVotePayload::new(self.id, self.election.term, self.chain.len() as u32)
+                    .as_bytes(),
+            );
+            self.send(&request_vote_packet);
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.election.become_leader(self.id);
+        self.system_log(format!("Became leader for term {}", self.election.term));
+        self.match_heights.clear();
+        self.advance_commit();
+        self.send_heartbeats();
+    }
+
+    // Moves the commit point up to the height a majority of the cluster holds, going
+    // by the heights peers last reported with GetChain
+    fn advance_commit(&mut self) {
+        let mut heights: Vec<usize> = self.match_heights.values().copied().collect();
+        heights.push(self.chain.len());
+        heights.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = self.cluster_size() / 2 + 1;
+        if let Some(&height) = heights.get(majority - 1) {
+            if height > self.committed_height {
+                self.committed_height = height;
+                self.system_log(format!("Committed blocks up to #{}", height));
+            }
+        }
+    }
+
+    fn send_heartbeats(&mut self) {
+        self.election.heartbeat_sent();
+        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
+        for peer in peers {
+            let heartbeat_packet = Packet::new(
+                self.id,
+                peer,
+                PacketType::Heartbeat,
+                HeartbeatPayload::new(
+                    self.id,
+                    self.election.term,
+                    self.id,
+                    self.chain.len() as u32,
+                    self.committed_height as u32,
+                    Self::tip_hash(&self.chain[..self.committed_height]),
+                )
+                .as_bytes(),
+            );
+            self.send(&heartbeat_packet);
+        }
+    }
+
+    fn reply_ack(&mut self, packet: &Packet) {
+        let ack_packet = Packet::new(
+            self.id,
+            packet.src,
+            PacketType::Ack,
+            AckPayload::new(packet.packet_id).as_bytes(),
+        );
+        self.send(&ack_packet);
+    }
+
+    fn send_probe(&mut self, peer: u16, address: &str) {
+        self.addr_table.insert(peer, address.to_string());
+        let handshake = Handshake::new(self.clock.now());
+        let probe_packet = Packet::new(
+            self.id,
+            peer,
+            PacketType::Probe,
+            ProbePayload::new(
+                self.id,
+                self.key_pair.public_key().as_ref().try_into().unwrap(),
+                handshake.ephemeral_key,
+            )
+            .as_bytes(),
+        );
+        self.handshakes.insert(peer, handshake);
+        self.send(&probe_packet);
+    }
+
+    fn handle_ack(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let ack_payload = AckPayload::from_bytes(&packet.payload)?;
+        // Only the packet's recipient may acknowledge it
+        if self
+            .pending_acks
+            .get(&ack_payload.packet_id)
+            .is_some_and(|(_, _, pending)| pending.dst == packet.src)
+        {
+            let (retries, sent_time, _) = self.pending_acks.remove(&ack_payload.packet_id).unwrap();
+            // A retransmitted packet's ack could answer any attempt, so only first
+            // attempts are timed
+            if retries == 0 {
+                if let Some(peer) = self.peers.get_mut(&packet.src) {
+                    peer.record_rtt(self.clock.now().saturating_sub(sent_time));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checks a joining node against the configured members, reporting it to the
+    // dashboard when rejected
+    fn admit(&mut self, packet: &Packet, addr: &str, node_id: u16, public_key: &[u8; 32]) -> bool {
+        let reason = if node_id != packet.src {
+            "node id does not match sender"
+        } else if !self.config.is_member(node_id, public_key) {
+            "public key is not registered"
+        } else {
+            return true;
+        };
+
+        self.rejected_peers += 1;
+        self.system_log(format!(
+            "Rejected node {} at {} with key {}: {} ({} rejected)",
+            node_id,
+            addr,
+            hex_string(public_key),
+            reason,
+            self.rejected_peers
+        ));
+        self.broadcast_message(
+            serde_json::json!({
+                "type": "rejected_peer",
+                "value": {
+                    "id": node_id,
+                    "address": addr,
+                    "public_key": hex_string(public_key),
+                    "reason": reason,
+                    "rejected": self.rejected_peers
+                }
+            })
+            .to_string()
+            .as_bytes(),
+        );
+        false
+    }
+
+    fn handle_probe(&mut self, packet: Packet, addr: &str) -> Result<(), ProtocolError> {
+        let probe_payload = ProbePayload::from_bytes(&packet.payload)?;
+        if !self.admit(
+            &packet,
+            addr,
+            probe_payload.node_id,
+            &probe_payload.public_key,
+        ) {
+            return Ok(());
+        }
+        if self.pin_key(probe_payload.node_id, &probe_payload.public_key)? {
+            self.system_log(format!(
+                "Added public key of {:?}: {}",
+                probe_payload.node_id,
+                hex_string(&probe_payload.public_key)
+            ));
+        }
+        self.addr_table
+            .insert(probe_payload.node_id, addr.to_string());
+
+        // When both nodes probe each other at once, the handshake opened by the
+        // lower id wins and the other one is declined with a zero key
+        let ephemeral_key = if self.id < packet.src && self.handshake_pending(packet.src) {
+            [0; 32]
+        } else {
+            self.handshakes.remove(&packet.src);
+            let handshake = Handshake::new(self.clock.now());
+            let ephemeral_key = handshake.ephemeral_key;
+            if let Some(key) =
+                handshake.finish(self.id, packet.src, &probe_payload.ephemeral_key, false)
+            {
+                self.establish_session(packet.src, key);
+            }
+            ephemeral_key
+        };
+
+        if !self.chain.is_empty() {
+            let sync_packet = Packet::new(
+                self.id,
+                probe_payload.node_id,
+                PacketType::Sync,
+                SyncPayload::new(
+                    self.id,
+                    self.key_pair.public_key().as_ref().try_into().unwrap(),
+                    self.chain.len() as u32,
+                    self.chain.last().unwrap().merkle_root,
+                    self.chain.last().unwrap().timestamp,
+                    ephemeral_key,
+                )
+                .as_bytes(),
+            );
+            self.send(&sync_packet);
+        } else {
+            let sync_packet = Packet::new(
+                self.id,
+                probe_payload.node_id,
+                PacketType::Sync,
+                SyncPayload::new(
+                    self.id,
+                    self.key_pair.public_key().as_ref().try_into().unwrap(),
+                    0,
+                    [0; 32],
+                    0,
+                    ephemeral_key,
+                )
+                .as_bytes(),
+            );
+            self.send(&sync_packet);
+        }
+
+        // Entries whose write is not known here go out as the oldest possible version
+        for (name, data) in self.cache.entries() {
+            let (timestamp, transaction_hash) = match self.latest_writes.get(&name) {
+                Some((CacheOperation::Set, timestamp, hash)) => (*timestamp, *hash),
+                _ => (0, [0; 32]),
+            };
+            let replicate_packet = Packet::new(
+                self.id,
+                probe_payload.node_id,
+                PacketType::Replicate,
+                ReplicatePayload::new(name, timestamp, transaction_hash, data).as_bytes(),
+            );
+            self.send(&replicate_packet);
+        }
+        Ok(())
+    }
+
+    // Pins the key of an admitted node, returning whether it is new. A node that
+    // already has a key keeps it, and presenting another one is an error
+    fn pin_key(&mut self, node_id: u16, public_key: &[u8; 32]) -> Result<bool, ProtocolError> {
+        match self.peer_public_keys.get(&node_id) {
+            Some(pinned) if pinned.as_slice() == public_key => Ok(false),
+            Some(_) => Err(ProtocolError::KeyMismatch(node_id)),
+            None => {
+                self.peer_public_keys.insert(node_id, public_key.to_vec());
+                self.gossiped_keys.remove(&node_id);
+                Ok(true)
+            }
+        }
+    }
+
+    fn handle_sync(&mut self, packet: &Packet, addr: &str) -> Result<(), ProtocolError> {
+        let sync_payload = SyncPayload::from_bytes(&packet.payload)?;
+        if !self.admit(packet, addr, sync_payload.node_id, &sync_payload.public_key) {
+            return Ok(());
+        }
+        if self.pin_key(sync_payload.node_id, &sync_payload.public_key)? {
+            self.system_log(format!(
+                "Added public key of {:?}: {}",
+                sync_payload.node_id,
+                hex_string(&sync_payload.public_key)
+            ));
+        }
+        self.addr_table
+            .insert(sync_payload.node_id, addr.to_string());
+
+        if sync_payload.ephemeral_key != [0; 32] {
+            if let Some(key) = self.handshakes.remove(&packet.src).and_then(|handshake| {
+                handshake.finish(self.id, packet.src, &sync_payload.ephemeral_key, true)
+            }) {
+                self.establish_session(packet.src, key);
+            }
+        }
+
+        // Chains are only taken from the leader
+        if self.election.leader == Some(sync_payload.node_id)
+            && self.chain.len() < sync_payload.chain_height as usize
+        {
+            self.request_chain(
+                sync_payload.node_id,
+                self.chain.len(),
+                Self::tip_hash(&self.chain),
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_handshake(
+        &mut self,
+        packet: &Packet,
+        authenticated: bool,
+        addr: &str,
+    ) -> Result<(), ProtocolError> {
+        let handshake_payload = HandshakePayload::from_bytes(&packet.payload)?;
+        if !authenticated {
+            self.accept_client(packet, &handshake_payload, addr)?;
+        } else if handshake_payload.reply {
+            if let Some(key) = self.handshakes.remove(&packet.src).and_then(|handshake| {
+                handshake.finish(self.id, packet.src, &handshake_payload.ephemeral_key, true)
+            }) {
+                self.establish_session(packet.src, key);
+            }
+            return Ok(());
+        }
+
+        let handshake = Handshake::new(self.clock.now());
+        let reply_packet = Packet::new(
+            self.id,
+            packet.src,
+            PacketType::Handshake,
+            HandshakePayload::new(
+                handshake.ephemeral_key,
+                true,
+                self.key_pair.public_key().as_ref().try_into().unwrap(),
+            )
+            .as_bytes(),
+        );
+        if let Some(key) =
+            handshake.finish(self.id, packet.src, &handshake_payload.ephemeral_key, false)
+        {
+            self.establish_session(packet.src, key);
+            self.send(&reply_packet);
+        }
+        Ok(())
+    }
+
+    // Checks a client's handshake, which must be signed with a key `clients` accepts.
+    // While a client's session is live, no other key can take over its id
+    fn accept_client(
+        &mut self,
+        packet: &Packet,
+        handshake_payload: &HandshakePayload,
+        addr: &str,
+    ) -> Result<(), ProtocolError> {
+        let public_key = handshake_payload.public_key;
+        if packet.src == self.id
+            || handshake_payload.reply
+            || !self.config.is_client(packet.src, &public_key)
+        {
+            return Err(ProtocolError::Unauthenticated {
+                src: packet.src,
+                packet_type: packet.packet_type,
+            });
+        }
+        if !packet.verify_signature(&public_key) {
+            return Err(ProtocolError::InvalidSignature(packet.src));
+        }
+        let lifetime = Duration::from_secs(self.config.session_lifetime);
+        if self
+            .client_keys
+            .get(&packet.src)
+            .is_some_and(|key| *key != public_key)
+            && self
+                .sessions
+                .get(&packet.src)
+                .is_some_and(|session| session.age(self.clock.now()) < lifetime)
+        {
+            return Err(ProtocolError::KeyMismatch(packet.src));
+        }
+        self.client_keys.insert(packet.src, public_key);
+        self.client_addrs.insert(packet.src, addr.to_string());
+        Ok(())
+    }
+
+    // Whether a handshake we opened with `peer` may still be answered
+    fn handshake_pending(&self, peer: u16) -> bool {
+        self.handshakes.get(&peer).is_some_and(|handshake| {
+            self.clock.now().saturating_sub(handshake.started) < HANDSHAKE_TIMEOUT
+        })
+    }
+
+    fn establish_session(&mut self, peer: u16, key: LessSafeKey) {
+        let now = self.clock.now();
+        if let Some(session) = self.sessions.get_mut(&peer) {
+            session.rotate(key, now);
+            self.system_log(format!("Rotated session key with {}", peer));
+        } else {
+            self.sessions.insert(peer, Session::new(key, now));
+            self.system_log(format!("Established encrypted session with {}", peer));
+        }
+    }
+
+    // Rekeys sessions with peers that have outlived the configured lifetime. Only the
+    // lower id of each pair starts a rekey so the two sides never race
+    fn check_sessions(&mut self) {
+        let lifetime = Duration::from_secs(self.config.session_lifetime);
+        let due: Vec<u16> = self
+            .sessions
+            .iter()
+            .filter(|(peer, session)| {
+                self.id < **peer
+                    && self.peer_public_keys.contains_key(peer)
+                    && session.age(self.clock.now()) >= lifetime
+                    && !self.handshake_pending(**peer)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in due {
+            let handshake = Handshake::new(self.clock.now());
+            let handshake_packet = Packet::new(
+                self.id,
+                peer,
+                PacketType::Handshake,
+                HandshakePayload::new(
+                    handshake.ephemeral_key,
+                    false,
+                    self.key_pair.public_key().as_ref().try_into().unwrap(),
+                )
+                .as_bytes(),
+            );
+            self.handshakes.insert(peer, handshake);
+            self.send(&handshake_packet);
+        }
+    }
+
+    fn handle_heartbeat(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let heartbeat_payload = HeartbeatPayload::from_bytes(&packet.payload)?;
+        Self::check_sender(packet, heartbeat_payload.leader)?;
+        if heartbeat_payload.term < self.election.term {
+            return Ok(());
+        }
+        if self.election.leader != Some(heartbeat_payload.leader) {
+            self.system_log(format!(
+                "Following leader {} in term {}",
+                heartbeat_payload.leader, heartbeat_payload.term
+            ));
+        }
+        self.election
+            .follow(heartbeat_payload.leader, heartbeat_payload.term);
+
+        // Blocks the leader reports committed are only taken as such if ours match
+        let commit_height = heartbeat_payload.commit_height as usize;
+        if commit_height > self.committed_height
+            && commit_height <= self.chain.len()
+            && Self::tip_hash(&self.chain[..commit_height]) == heartbeat_payload.commit_hash
+        {
+            self.committed_height = commit_height;
+        }
+
+        // Also tells the leader how far this node's chain reaches, for its commit point
+        if self.fork_chain.is_empty() {
+            self.request_chain(
+                heartbeat_payload.leader,
+                self.chain.len(),
+                Self::tip_hash(&self.chain),
+            );
+        } else {
+            self.request_chain(
+                heartbeat_payload.leader,
+                self.fork_chain.len(),
+                Self::tip_hash(&self.fork_chain),
+            );
+        }
+        Ok(())
+    }
+
+    fn handle_peer_list(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let peer_list_payload = PeerListPayload::from_bytes(&packet.payload)?;
+        let via = format!("node {}", packet.src);
+        for entry in &peer_list_payload.peers {
+            self.discover(entry, &entry.address, &via);
+        }
+        Ok(())
+    }
+
+    // Disconnects a peer that is shutting down, dropping anything still queued for it.
+    // Its key stays pinned so the blocks it signed still validate
+    fn handle_leave(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        self.peers.remove(&packet.src);
+        self.addr_table.remove(&packet.src);
+        self.sessions.remove(&packet.src);
+        self.handshakes.remove(&packet.src);
+        self.held_packets.remove(&packet.src);
+        self.pending_acks
+            .retain(|_, (_, _, pending)| pending.dst != packet.src);
+        if self.election.leader == Some(packet.src) {
+            self.election.leader = None;
+        }
+        self.system_log(format!("Peer {} left", packet.src));
+        self.broadcast_peers();
+        Ok(())
+    }
+
+    fn handle_request_vote(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let request_vote_payload = RequestVotePayload::from_bytes(&packet.payload)?;
+        Self::check_sender(packet, request_vote_payload.candidate)?;
+        let was_leader = self.election.is_leader();
+        let granted = self.election.grant_vote(
+            request_vote_payload.candidate,
+            request_vote_payload.term,
+            request_vote_payload.chain_height as usize >= self.chain.len(),
+        );
+        if was_leader && self.election.role == Role::Follower {
+            self.system_log(format!(
+                "Stepped down as leader for term {}",
+                self.election.term
+            ));
+        }
+        self.system_log(format!(
+            "{} vote for node {} in term {}",
+            if granted { "Granted" } else { "Denied" },
+            request_vote_payload.candidate,
+            request_vote_payload.term
+        ));
+
+        let vote_packet = Packet::new(
+            self.id,
+            request_vote_payload.candidate,
+            PacketType::Vote,
+            VotePayload::new(self.id, self.election.term, granted).as_bytes(),
+        );
+        self.send(&vote_packet);
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let vote_payload = VotePayload::from_bytes(&packet.payload)?;
+        Self::check_sender(packet, vote_payload.node_id)?;
+        if self.election.observe_term(vote_payload.term) {
+            self.system_log(format!(
+                "Abandoned election, node {} is in term {}",
+                vote_payload.node_id, vote_payload.term
+            ));
+            return Ok(());
+        }
+        if vote_payload.granted
+            && self.election.record_vote(
+                vote_payload.node_id,
+                vote_payload.term,
+                self.cluster_size(),
+            )
+        {
+            self.become_leader();
+        }
+        Ok(())
+    }
+
+    // Election packets speak only for their sender, so one peer cannot lead, stand or
+    // vote in the name of another
+    fn check_sender(packet: &Packet, claimed: u16) -> Result<(), ProtocolError> {
+        if claimed != packet.src {
+            return Err(ProtocolError::SenderMismatch {
+                src: packet.src,
+                claimed,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks that a client request is signed by a key accepted for the client and that
+    // the access rules allow it. Refused requests are answered with an Error packet and,
+    // when they fail only on access, recorded on the chain if `audit_denied` is set
+    fn authorize_request(
+        &mut self,
+        packet: &Packet,
+        operation: CacheOperation,
+        request_payload: &RequestPayload,
+        request_hash: [u8; 32],
+    ) -> bool {
+        let client_signature = request_payload.client_signature;
+        let now = self.clock.now();
+        self.expire_seen_requests();
+        let code = if !self
+            .config
+            .is_client(packet.src, &client_signature.public_key)
+            || self.client_keys.get(&packet.src) != Some(&client_signature.public_key)
+        {
+            ErrorCode::UnknownClient
+        } else if !client_signature.verify(
+            packet.src,
+            operation,
+            &request_payload.name,
+            &request_hash,
+        ) {
+            ErrorCode::InvalidClientSignature
+        } else if client_signature.node_id != self.id
+            || !client_signature.is_fresh(now, MAX_REQUEST_AGE)
+            || self
+                .seen_requests
+                .insert(client_signature.signature, client_signature.timestamp)
+                .is_some()
+        {
+            ErrorCode::StaleRequest
+        } else if !self
+            .config
+            .is_allowed(packet.src, &request_payload.name, operation.into())
+        {
+            ErrorCode::AccessDenied
+        } else {
+            return true;
+        };
+
+        self.system_log(format!(
+            "Rejected {:?} request for {:?} from client {}: {}",
+            operation, request_payload.name, packet.src, code
+        ));
+        self.send_error(packet, code, request_payload.name.clone());
+
+        if code == ErrorCode::AccessDenied && self.config.audit_denied {
+            let prev_value_hash = self
+                .cache
+                .value_hash(&request_payload.name)
+                .unwrap_or([0; 32]);
+            self.create_transaction(
+                Transaction::new(
+                    self.clock.as_ref(),
+                    self.id,
+                    packet.src,
+                    request_payload.name.clone(),
+                    operation,
+                    request_hash,
+                    prev_value_hash,
+                    client_signature,
+                )
+                .denied(),
+            );
+        }
+        false
+    }
+
+    // Tells the client why request `packet` for `name` was refused
+    fn send_error(&mut self, packet: &Packet, code: ErrorCode, name: String) {
+        let error_packet = Packet::new(
+            self.id,
+            packet.src,
+            PacketType::Error,
+            ErrorPayload::new(packet.packet_id, code, name).as_bytes(),
+        );
+        self.send(&error_packet);
+    }
+
+    // Forgets signatures old enough to be refused as stale anyway
+    fn expire_seen_requests(&mut self) {
+        let now = self.clock.now();
+        self.seen_requests
+            .retain(|_, timestamp| now.abs_diff(*timestamp) <= MAX_REQUEST_AGE);
+    }
+
+    fn handle_set_data(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let request_payload = RequestPayload::from_bytes(&packet.payload)?;
+        let value_hash = sha256(&request_payload.data);
+        if !self.authorize_request(packet, CacheOperation::Set, &request_payload, value_hash) {
+            return Ok(());
+        }
+        let name = request_payload.name;
+        let prev_value_hash = self.cache.value_hash(&name).unwrap_or([0; 32]);
+        let txn = self.after_latest(Transaction::new(
+            self.clock.as_ref(),
+            self.id,
+            packet.src,
+            name.clone(),
+            CacheOperation::Set,
+            value_hash,
+            prev_value_hash,
+            request_payload.client_signature,
+        ));
+        if let Err(e) = self.cache.set(&name, &request_payload.data) {
+            self.system_log(format!("Failed to cache data {:?}: {}", name, e));
+            self.send_error(packet, ErrorCode::StorageFailed, name);
+            return Ok(());
+        }
+        self.system_log(format!(
+            "Cached data {:?} ({:?} bytes)",
+            name,
+            request_payload.data.len()
+        ));
+        self.confirm(packet, txn.hash);
+        self.replicate(&txn, request_payload.data);
+        self.create_transaction(txn);
+        self.broadcast_message(self.cache_message().as_bytes());
+        Ok(())
+    }
+
+    fn handle_get_data(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let request_payload = RequestPayload::from_bytes(&packet.payload)?;
+        if !self.authorize_request(packet, CacheOperation::Get, &request_payload, [0; 32]) {
+            return Ok(());
+        }
+        let name = request_payload.name.clone();
+        let data = self.cache.get(name.as_str());
+        let value_hash = data.as_ref().map(|data| sha256(data)).unwrap_or([0; 32]);
+        match data {
+            Some(data) => {
+                let data_packet = Packet::new(
+                    self.id,
+                    packet.src,
+                    PacketType::Data,
+                    DataPayload::new(name, data).as_bytes(),
+                );
+                self.send(&data_packet);
+            }
+            None => self.send_error(packet, ErrorCode::NotFound, name),
+        }
+
+        self.broadcast_message(self.cache_message().as_bytes());
+
+        self.create_transaction(Transaction::new(
+            self.clock.as_ref(),
+            self.id,
+            packet.src,
+            request_payload.name,
+            CacheOperation::Get,
+            value_hash,
+            value_hash,
+            request_payload.client_signature,
+        ));
+        Ok(())
+    }
+
+    fn handle_delete_data(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let request_payload = RequestPayload::from_bytes(&packet.payload)?;
+        if !self.authorize_request(packet, CacheOperation::Delete, &request_payload, [0; 32]) {
+            return Ok(());
+        }
+        let name = request_payload.name;
+        match self.delete(packet.src, name.clone(), request_payload.client_signature) {
+            Ok(transaction_hash) => self.confirm(packet, transaction_hash),
+            Err(_) => self.send_error(packet, ErrorCode::StorageFailed, name),
+        }
+        Ok(())
+    }
+
+    // Deletes `name` on behalf of `client_id`, records the delete for the next block and
+    // shares it with peers. Returns the hash of the recording transaction
+    fn delete(
+        &mut self,
+        client_id: u16,
+        name: String,
+        client_signature: ClientSignature,
+    ) -> io::Result<[u8; 32]> {
+        let prev_value_hash = self.cache.value_hash(&name).unwrap_or([0; 32]);
+        let txn = self.after_latest(Transaction::new(
+            self.clock.as_ref(),
+            self.id,
+            client_id,
+            name.clone(),
+            CacheOperation::Delete,
+            [0; 32],
+            prev_value_hash,
+            client_signature,
+        ));
+        if let Err(e) = self.cache.delete(&name) {
+            self.system_log(format!("Failed to delete data {:?}: {}", name, e));
+            return Err(e);
         }
+        self.system_log(format!("Deleted data {:?}", name));
+        self.replicate(&txn, vec![]);
+        let transaction_hash = txn.hash;
+        self.create_transaction(txn);
+        self.broadcast_message(self.cache_message().as_bytes());
+        Ok(transaction_hash)
     }
 
-    fn sign(&self, message: Vec<u8>) -> Signature {
-        self.key_pair.sign(message.as_ref())
+    // Deletes `name` from the dashboard. The node makes the request as its own client,
+    // signed with its node key, so peers can verify the transaction like any other
+    fn delete_from_web(&mut self, name: String) {
+        if name.is_empty() || name.len() > 64 {
+            self.system_log(format!(
+                "Ignored dashboard delete of invalid name {:?}",
+                name
+            ));
+            return;
+        }
+        let client_signature = ClientSignature::sign(
+            &self.key_pair,
+            self.id,
+            self.id,
+            CacheOperation::Delete,
+            &name,
+            [0; 32],
+            self.clock.now(),
+        );
+        let _ = self.delete(self.id, name, client_signature);
     }
 
-    fn verify(&self, message: &[u8], sig_bytes: &[u8], public_key: &[u8]) -> bool {
-        let verify_key = UnparsedPublicKey::new(&signature::ED25519, public_key);
-        verify_key.verify(message, sig_bytes).is_ok()
+    // Orders a write made here after the latest write of its name held here. With
+    // timestamps in whole seconds, one in the same second as the held write would
+    // otherwise fall to the hash order and could lose to the older write
+    fn after_latest(&self, txn: Transaction) -> Transaction {
+        match self.latest_writes.get(&txn.data_name) {
+            Some((_, timestamp, _)) if *timestamp >= txn.timestamp => txn.at(timestamp + 1),
+            _ => txn,
+        }
     }
 
-    fn create_transaction(&mut self, client_id: u16, data_name: &str, operation: CacheOperation) {
-        let txn = Transaction::new(self.id, client_id, data_name.to_string(), operation);
-        self.system_log(format!(
-            "Created transaction: client {} {:?} data {:?} at node {} on {}",
-            txn.client_id, txn.operation, txn.data_name, self.id, txn.timestamp
-        ));
-
-        let sig = self.sign(txn.as_bytes());
-        let txn_payload = TransactionPayload::new(txn.clone(), sig.as_ref().try_into().unwrap());
-
-        self.pending_transactions.insert(txn.hash, txn.clone());
+    // Tells the client its request was applied, naming the transaction that records it
+    fn confirm(&mut self, request_packet: &Packet, transaction_hash: [u8; 32]) {
+        let confirm_packet = Packet::new(
+            self.id,
+            request_packet.src,
+            PacketType::Confirm,
+            ConfirmPayload::new(request_packet.packet_id, transaction_hash).as_bytes(),
+        );
+        self.send(&confirm_packet);
+    }
 
+    // Shares the write or delete recorded by `txn` with every peer
+    fn replicate(&mut self, txn: &Transaction, data: Vec<u8>) {
+        let packet_type = match txn.operation {
+            CacheOperation::Delete => PacketType::ReplicateDelete,
+            _ => PacketType::Replicate,
+        };
+        let replicate_payload =
+            ReplicatePayload::new(txn.data_name.clone(), txn.timestamp, txn.hash, data);
         let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
         for peer in peers {
-            let txn_packet = Packet::new(
-                self.id,
-                peer,
-                PacketType::Transaction,
-                txn_payload.as_bytes(),
-            );
-
-            self.send(&txn_packet);
+            let replicate_packet =
+                Packet::new(self.id, peer, packet_type, replicate_payload.as_bytes());
+            self.send(&replicate_packet);
         }
-
-        self.web_server.broadcast_message(
-            serde_json::json!({
-                "type": "transaction",
-                "value": txn
-            })
-            .to_string()
-            .as_bytes(),
-        );
     }
 
-    fn create_block(&mut self) {
-        let transactions = self.pending_transactions.values().cloned().collect();
-        self.pending_transactions.clear();
-        let block = Block::new(
-            transactions,
-            if self.chain.is_empty() {
-                [0; 32]
-            } else {
-                self.chain.last().unwrap().merkle_root
-            },
-        );
-
-        let block_payload = BlockPayload::new(block.clone());
+    fn handle_replicate(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        if !self.peer_public_keys.contains_key(&packet.src) {
+            self.system_log(format!(
+                "Ignored replicated data from unknown node {}",
+                packet.src
+            ));
+            return Ok(());
+        }
+        let replicate_payload = ReplicatePayload::from_bytes(&packet.payload)?;
+        if !self.is_replicated_latest(&replicate_payload) {
+            self.system_log(format!(
+                "Ignored replicated data {:?} from node {}: a newer write is held",
+                replicate_payload.name, packet.src
+            ));
+            return Ok(());
+        }
+        if let Err(e) = self
+            .cache
+            .set(&replicate_payload.name, &replicate_payload.data)
+        {
+            self.system_log(format!(
+                "Failed to cache replicated data {:?}: {}",
+                replicate_payload.name, e
+            ));
+            return Ok(());
+        }
+        self.record_replicated(&replicate_payload, CacheOperation::Set);
         self.system_log(format!(
-            "Created block #{:?} {}",
-            self.chain.len(),
-            hex_string(&block.merkle_root)
+            "Replicated data {:?} ({:?} bytes) from node {}",
+            replicate_payload.name,
+            replicate_payload.data.len(),
+            packet.src
         ));
-        self.chain.push(block.clone());
+        self.broadcast_message(self.cache_message().as_bytes());
+        Ok(())
+    }
 
-        let peers: Vec<u16> = self.peer_public_keys.keys().copied().collect();
-        for peer in peers {
-            let block_packet =
-                Packet::new(self.id, peer, PacketType::Block, block_payload.as_bytes());
-            self.send(&block_packet);
+    fn handle_replicate_delete(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        if !self.peer_public_keys.contains_key(&packet.src) {
+            self.system_log(format!(
+                "Ignored replicated delete from unknown node {}",
+                packet.src
+            ));
+            return Ok(());
+        }
+        let replicate_payload = ReplicatePayload::from_bytes(&packet.payload)?;
+        if !self.is_replicated_latest(&replicate_payload) {
+            self.system_log(format!(
+                "Ignored replicated delete of {:?} from node {}: a newer write is held",
+                replicate_payload.name, packet.src
+            ));
+            return Ok(());
+        }
+        if let Err(e) = self.cache.delete(&replicate_payload.name) {
+            self.system_log(format!(
+                "Failed to apply replicated delete of {:?}: {}",
+                replicate_payload.name, e
+            ));
+            return Ok(());
         }
+        self.record_replicated(&replicate_payload, CacheOperation::Delete);
+        self.system_log(format!(
+            "Replicated delete of {:?} from node {}",
+            replicate_payload.name, packet.src
+        ));
+        self.broadcast_message(self.cache_message().as_bytes());
+        Ok(())
+    }
 
-        self.web_server.broadcast_message(
-            serde_json::json!({
-                "type": "block",
-                "value": {
-                    "timestamp": block.timestamp,
-                    "merkle_root": hex_string(&block.merkle_root),
-                    "transaction_count": block.transactions.len()
-                }
-            })
-            .to_string()
-            .as_bytes(),
+    fn is_replicated_latest(&self, replicate_payload: &ReplicatePayload) -> bool {
+        Self::is_latest(
+            &self.latest_writes,
+            &replicate_payload.name,
+            replicate_payload.timestamp,
+            replicate_payload.transaction_hash,
+        )
+    }
+
+    fn record_replicated(
+        &mut self,
+        replicate_payload: &ReplicatePayload,
+        operation: CacheOperation,
+    ) {
+        Self::record_version(
+            &mut self.latest_writes,
+            &replicate_payload.name,
+            operation,
+            replicate_payload.timestamp,
+            replicate_payload.transaction_hash,
         );
     }
 
-    fn reply_ack(&mut self, packet: &Packet) {
-        let ack_packet = Packet::new(
+    fn handle_get_proof(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let get_proof_payload = GetProofPayload::from_bytes(&packet.payload)?;
+        let Some(proof_payload) = self.find_proof(&get_proof_payload.transaction_hash) else {
+            let transaction_hash = hex_string(&get_proof_payload.transaction_hash);
+            self.system_log(format!(
+                "No block contains transaction {}",
+                transaction_hash
+            ));
+            self.send_error(packet, ErrorCode::NotFound, transaction_hash);
+            return Ok(());
+        };
+        self.system_log(format!(
+            "Sending proof of transaction {} in block #{} to {:?}",
+            hex_string(&get_proof_payload.transaction_hash),
+            proof_payload.block_height,
+            packet.src
+        ));
+        let proof_packet = Packet::new(
             self.id,
             packet.src,
-            PacketType::Ack,
-            AckPayload::new(packet.packet_id).as_bytes(),
+            PacketType::Proof,
+            proof_payload.as_bytes(),
         );
-        self.send(&ack_packet);
+        self.send(&proof_packet);
+        Ok(())
     }
 
-    fn send_probe(&mut self, orchestrator_address: &str) {
-        self.addr_table.insert(0, orchestrator_address.to_string());
-        let probe_packet = Packet::new(
+    fn request_chain(&mut self, peer: u16, from_height: usize, tip_hash: [u8; 32]) {
+        let get_chain_payload = GetChainPayload::new(from_height as u32, tip_hash, CHAIN_PAGE_SIZE);
+        let get_chain_packet = Packet::new(
             self.id,
-            0,
-            PacketType::Probe,
-            ProbePayload::new(
-                self.id,
-                self.key_pair.public_key().as_ref().try_into().unwrap(),
-            )
-            .as_bytes(),
+            peer,
+            PacketType::GetChain,
+            get_chain_payload.as_bytes(),
         );
-        self.send(&probe_packet);
-    }
-
-    fn handle_ack(&mut self, packet: &Packet) {
-        let ack_payload = AckPayload::from_bytes(&packet.payload);
-        self.pending_acks.remove(&ack_payload.packet_id);
+        self.send(&get_chain_packet);
     }
 
-    fn handle_probe(&mut self, packet: Packet) {
-        let probe_payload = ProbePayload::from_bytes(&packet.payload);
-        self.peer_public_keys
-            .insert(probe_payload.node_id, probe_payload.public_key.to_vec());
-
+    fn handle_chain(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let chain_payload = ChainPayload::from_bytes(&packet.payload)?;
+        let start_height = chain_payload.start_height as usize;
+        let chain_height = chain_payload.chain_height as usize;
         self.system_log(format!(
-            "Added public key of {:?}: {}",
-            probe_payload.node_id,
-            hex_string(&probe_payload.public_key)
+            "Received blocks #{}..#{} of {} from {:?}",
+            start_height,
+            start_height + chain_payload.chain.len(),
+            chain_height,
+            packet.src
         ));
-
-        if !self.chain.is_empty() {
-            let sync_packet = Packet::new(
-                self.id,
-                probe_payload.node_id,
-                PacketType::Sync,
-                SyncPayload::new(
-                    self.id,
-                    self.key_pair.public_key().as_ref().try_into().unwrap(),
-                    self.chain.len() as u32,
-                    self.chain.last().unwrap().merkle_root,
-                    self.chain.last().unwrap().timestamp,
-                )
-                .as_bytes(),
-            );
-            self.send(&sync_packet);
-        } else {
-            let sync_packet = Packet::new(
-                self.id,
-                probe_payload.node_id,
-                PacketType::Sync,
-                SyncPayload::new(
-                    self.id,
-                    self.key_pair.public_key().as_ref().try_into().unwrap(),
-                    0,
-                    [0; 32],
-                    0,
-                )
-                .as_bytes(),
-            );
-            self.send(&sync_packet);
+        if self.election.leader != Some(packet.src) {
+            self.system_log(format!(
+                "Ignored chain from {:?}: leader is {:?}",
+                packet.src, self.election.leader
+            ));
+            return Ok(());
         }
-    }
-
-    fn handle_sync(&mut self, packet: &Packet) {
-        let sync_payload = SyncPayload::from_bytes(&packet.payload);
-        self.peer_public_keys
-            .insert(sync_payload.node_id, sync_payload.public_key.to_vec());
-
-        self.system_log(format!(
-            "Added public key of {:?}: {}",
-            sync_payload.node_id,
-            hex_string(&sync_payload.public_key)
-        ));
+        let Some(first_block) = chain_payload.chain.first() else {
+            return Ok(());
+        };
 
-        if self.chain.len() < sync_payload.chain_height as usize {
-            let get_chain_packet =
-                Packet::new(self.id, sync_payload.node_id, PacketType::GetChain, vec![]);
-            self.send(&get_chain_packet);
+        let continues_fork = !self.fork_chain.is_empty()
+            && start_height == self.fork_chain.len()
+            && first_block.prev_block_hash == Self::tip_hash(&self.fork_chain);
+        if !continues_fork
+            && start_height == self.chain.len()
+            && first_block.prev_block_hash == Self::tip_hash(&self.chain)
+        {
+            // The page extends our own chain
+            if let Err((height, e)) = self.validate_blocks(
+                start_height,
+                Self::tip_hash(&self.chain),
+                &chain_payload.chain,
+            ) {
+                self.system_log(format!(
+                    "Rejected chain from {:?}: block #{} {}",
+                    packet.src, height, e
+                ));
+                return Ok(());
+            }
+            if let Err(e) = self.append_blocks(chain_payload.chain) {
+                self.system_log(format!(
+                    "Failed to persist block #{}: {}",
+                    self.chain.len(),
+                    e
+                ));
+                return Ok(());
+            }
+            self.fork_chain.clear();
+            if self.chain.len() < chain_height {
+                self.request_chain(packet.src, self.chain.len(), Self::tip_hash(&self.chain));
+            }
+            return Ok(());
         }
-    }
-
-    fn handle_set_data(&mut self, packet: &Packet) {
-        let data_payload = DataPayload::from_bytes(&packet.payload);
-        self.cache
-            .set(data_payload.name.as_str(), data_payload.data.as_ref());
-        self.system_log(format!(
-            "Cached data {:?} ({:?} bytes)",
-            data_payload.name,
-            data_payload.data.len()
-        ));
-        self.create_transaction(packet.src, data_payload.name.as_str(), CacheOperation::Set);
-        self.web_server.broadcast_message(
-            serde_json::json!({
-                "type": "cache",
-                "value": self.cache.metadata()
-            })
-            .to_string()
-            .as_bytes(),
-        );
-    }
 
-    fn handle_get_data(&mut self, packet: &Packet) {
-        let data_payload = DataPayload::from_bytes(&packet.payload);
-        let name = data_payload.name.clone();
-        let data = self.cache.get(name.as_str());
-        if let Some(data) = data {
-            let data_packet = Packet::new(
-                self.id,
+        // The leader's chain diverges from ours, so it is resent from height 0 and
+        // only replaces our chain once fully downloaded and validated
+        if start_height == 0 {
+            self.fork_chain.clear();
+        } else if !continues_fork {
+            // A page answering an outdated request
+            return Ok(());
+        }
+        if let Err((height, e)) = self.validate_blocks(
+            start_height,
+            Self::tip_hash(&self.fork_chain),
+            &chain_payload.chain,
+        ) {
+            self.system_log(format!(
+                "Rejected chain from {:?}: block #{} {}",
+                packet.src, height, e
+            ));
+            self.fork_chain.clear();
+            return Ok(());
+        }
+        self.fork_chain.extend(chain_payload.chain);
+        if self.fork_chain.len() < chain_height {
+            self.request_chain(
                 packet.src,
-                PacketType::Data,
-                DataPayload::new(name, data).as_bytes(),
+                self.fork_chain.len(),
+                Self::tip_hash(&self.fork_chain),
             );
-            self.send(&data_packet);
+            return Ok(());
         }
 
-        self.web_server.broadcast_message(
-            serde_json::json!({
-                "type": "cache",
-                "value": self.cache.metadata()
-            })
-            .to_string()
-            .as_bytes(),
-        );
-
-        self.create_transaction(packet.src, data_payload.name.as_str(), CacheOperation::Get);
-    }
-
-    fn handle_chain(&mut self, packet: &Packet) {
-        let chain_payload = ChainPayload::from_bytes(&packet.payload);
+        let fork_chain = std::mem::take(&mut self.fork_chain);
+        let common_height = self
+            .chain
+            .iter()
+            .zip(&fork_chain)
+            .take_while(|(block, fork_block)| block.merkle_root == fork_block.merkle_root)
+            .count();
+        if common_height < self.committed_height {
+            self.system_log(format!(
+                "Rejected chain from {:?}: it replaces committed block #{}",
+                packet.src, common_height
+            ));
+            return Ok(());
+        }
         self.system_log(format!(
-            "Received chain of {} blocks from {:?}",
-            chain_payload.chain.len(),
+            "Replaced local chain of {} blocks with chain of {} blocks from {:?}",
+            self.chain.len(),
+            fork_chain.len(),
             packet.src
         ));
-        self.chain = chain_payload.chain;
+        if let Err(e) = self.store.truncate(common_height) {
+            self.system_log(format!("Failed to truncate block store: {}", e));
+            return Ok(());
+        }
+        let dropped: Vec<Transaction> = self
+            .chain
+            .drain(common_height..)
+            .flat_map(|block| block.transactions)
+            .collect();
+        for txn in &dropped {
+            self.sealed_transactions.remove(&txn.hash);
+        }
+        if let Err(e) = self.append_blocks(fork_chain.into_iter().skip(common_height).collect()) {
+            self.system_log(format!(
+                "Failed to persist block #{}: {}",
+                self.chain.len(),
+                e
+            ));
+        }
+        // Transactions only the dropped blocks held are pending again, for a later block
+        for txn in dropped {
+            if !self.sealed_transactions.contains(&txn.hash) {
+                self.pending_transactions.insert(txn.hash, txn);
+            }
+        }
+        self.rebuild_latest_writes();
+        Ok(())
     }
 
-    fn handle_get_chain(&mut self, packet: &Packet) {
-        let chain_payload = ChainPayload::new(self.chain.clone());
+    fn handle_get_chain(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let get_chain_payload = GetChainPayload::from_bytes(&packet.payload)?;
+        let from_height = get_chain_payload.from_height as usize;
+        // Resend from genesis if the requester's tip is not on our chain
+        let start_height = if from_height <= self.chain.len()
+            && Self::tip_hash(&self.chain[..from_height]) == get_chain_payload.tip_hash
+        {
+            from_height
+        } else {
+            0
+        };
+        if self.election.is_leader() && start_height == from_height {
+            self.match_heights.insert(packet.src, from_height);
+            self.advance_commit();
+        }
+        if start_height == self.chain.len() {
+            // The requester is up to date
+            return Ok(());
+        }
+        let page_size = get_chain_payload.max_blocks.clamp(1, CHAIN_PAGE_SIZE) as usize;
+        // Pages are also cut by encoded size so large blocks still fit in reassembly;
+        // the first block always goes, as no smaller page could carry it
+        let mut end_height = start_height;
+        let mut page_bytes = 0;
+        for block in &self.chain[start_height..] {
+            let block_bytes = 4 + block.as_bytes().len();
+            if end_height - start_height == page_size
+                || (end_height > start_height && page_bytes + block_bytes > CHAIN_PAGE_BYTES)
+            {
+                break;
+            }
+            page_bytes += block_bytes;
+            end_height += 1;
+        }
+
+        let chain_payload = ChainPayload::new(
+            start_height as u32,
+            self.chain.len() as u32,
+            self.chain[start_height..end_height].to_vec(),
+        );
         self.system_log(format!(
-            "Sending chain of {} blocks to {:?}",
-            chain_payload.chain.len(),
+            "Sending blocks #{}..#{} of {} to {:?}",
+            start_height,
+            end_height,
+            self.chain.len(),
             packet.src
         ));
         let chain_packet = Packet::new(
@@ -437,7 +2519,7 @@ impl Node {
         );
         self.send(&chain_packet);
 
-        self.web_server.broadcast_message(
+        self.broadcast_message(
             serde_json::json!({
                 "type": "chain",
                 "value": self.chain
@@ -445,24 +2527,39 @@ impl Node {
             .to_string()
             .as_bytes(),
         );
+        Ok(())
     }
 
-    fn handle_transaction(&mut self, packet: &Packet) {
-        let transaction_payload = TransactionPayload::from_bytes(&packet.payload);
-        let Some(public_key) = self.peer_public_keys.get(&packet.src) else {
-            self.system_log(format!("No public key found for node {}", packet.src));
-            return;
+    fn handle_transaction(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let transaction_payload = TransactionPayload::from_bytes(&packet.payload)?;
+        let node_id = transaction_payload.transaction.node_id;
+        let Some(public_key) = self.peer_public_keys.get(&node_id) else {
+            self.system_log(format!("No public key found for node {}", node_id));
+            return Ok(());
         };
-        let signature = transaction_payload.signature;
-        if self.verify(
-            transaction_payload.transaction.as_bytes().as_ref(),
-            signature.as_ref(),
-            public_key,
-        ) {
+        if transaction_payload.transaction.calculate_hash() == transaction_payload.transaction.hash
+            && transaction_payload.transaction.verify_signature(public_key)
+            && transaction_payload.transaction.verify_client_signature()
+        {
+            if self
+                .sealed_transactions
+                .contains(&transaction_payload.transaction.hash)
+            {
+                self.system_log(format!(
+                    "Ignored transaction {} already on the chain",
+                    hex_string(&transaction_payload.transaction.hash)
+                ));
+                return Ok(());
+            }
             self.system_log(format!(
-                "Transaction verified: client {} {:?} data {:?} at node {} on {}",
+                "Transaction verified: client {} {:?}{} data {:?} at node {} on {}",
                 transaction_payload.transaction.client_id,
                 transaction_payload.transaction.operation,
+                if transaction_payload.transaction.denied {
+                    " (denied)"
+                } else {
+                    ""
+                },
                 transaction_payload.transaction.data_name,
                 transaction_payload.transaction.node_id,
                 transaction_payload.transaction.timestamp
@@ -471,8 +2568,9 @@ impl Node {
                 transaction_payload.transaction.hash,
                 transaction_payload.transaction.clone(),
             );
+            Self::record_write(&mut self.latest_writes, &transaction_payload.transaction);
 
-            self.web_server.broadcast_message(
+            self.broadcast_message(
                 serde_json::json!({
                     "type": "transaction",
                     "value": transaction_payload.transaction
@@ -490,26 +2588,55 @@ impl Node {
                 transaction_payload.transaction.timestamp
             ));
         }
+        Ok(())
     }
 
-    fn handle_block(&mut self, packet: &Packet) {
-        let block_payload = BlockPayload::from_bytes(&packet.payload);
+    fn handle_block(&mut self, packet: &Packet) -> Result<(), ProtocolError> {
+        let block_payload = BlockPayload::from_bytes(&packet.payload)?;
+        if self.election.leader != Some(block_payload.block.leader) {
+            self.system_log(format!(
+                "Rejected block {} from {:?}: produced by node {} but leader is {:?}",
+                hex_string(&block_payload.block.merkle_root),
+                packet.src,
+                block_payload.block.leader,
+                self.election.leader
+            ));
+            return Ok(());
+        }
+        let prev_block_hash = self
+            .chain
+            .last()
+            .map(|block| block.merkle_root)
+            .unwrap_or([0; 32]);
+        if let Err(e) = block_payload
+            .block
+            .validate(&prev_block_hash, &self.known_public_keys())
+        {
+            self.system_log(format!(
+                "Rejected block #{:?} {} from {:?}: {}",
+                self.chain.len(),
+                hex_string(&block_payload.block.merkle_root),
+                packet.src,
+                e
+            ));
+            return Ok(());
+        }
         self.system_log(format!(
             "Received block #{:?} {}",
             self.chain.len(),
             hex_string(&block_payload.block.merkle_root)
         ));
-        self.chain.push(block_payload.block.clone());
-
-        for txn in &block_payload.block.transactions {
-            self.pending_transactions.remove(&txn.hash);
+        if let Err(e) = self.append_block(block_payload.block.clone()) {
             self.system_log(format!(
-                "Removed transaction {} from pending transactions",
-                hex_string(&txn.hash)
+                "Failed to persist block #{:?} {}: {}",
+                self.chain.len(),
+                hex_string(&block_payload.block.merkle_root),
+                e
             ));
+            return Ok(());
         }
 
-        self.web_server.broadcast_message(
+        self.broadcast_message(
             serde_json::json!({
             "type": "block",
             "value": {
@@ -521,55 +2648,146 @@ impl Node {
             .to_string()
             .as_bytes(),
         );
+        Ok(())
     }
 
     fn send(&mut self, packet: &Packet) {
+        // Dead peers are only pinged, so they are noticed when they come back
+        if packet.packet_type != PacketType::Ping
+            && self
+                .peers
+                .get(&packet.dst)
+                .is_some_and(|peer| peer.state == PeerState::Dead)
+        {
+            return;
+        }
+        if packet.packet_type.carries_data() && !self.sessions.contains_key(&packet.dst) {
+            self.hold(packet);
+            return;
+        }
+        match packet.fragment() {
+            Ok(fragments) => {
+                for fragment in fragments {
+                    self.transmit(&fragment);
+                }
+            }
+            Err(e) => self.network_log(format!(
+                "Failed to send {:?}-0x{:X} to {:?}: {}",
+                packet.packet_type, packet.packet_id, packet.dst, e
+            )),
+        }
+    }
+
+    // Keeps a data packet until a session with its destination is established, so it
+    // never goes out in the clear
+    fn hold(&mut self, packet: &Packet) {
+        let held = self.held_packets.entry(packet.dst).or_default();
+        if held.len() == MAX_HELD_PACKETS {
+            held.pop_front();
+        }
+        held.push_back(packet.clone());
+        self.network_log(format!(
+            "Holding {:?}-0x{:X} for {:?} until a session is established",
+            packet.packet_type, packet.packet_id, packet.dst
+        ));
+    }
+
+    // Sends the packets held for `peer` once there is a session with it
+    fn release_held(&mut self, peer: u16) {
+        if !self.sessions.contains_key(&peer) {
+            return;
+        }
+        for packet in self.held_packets.remove(&peer).unwrap_or_default() {
+            self.send(&packet);
+        }
+    }
+
+    fn transmit(&mut self, packet: &Packet) {
+        let mut packet = packet.clone();
+        self.sequence += 1;
+        packet.sequence = self.sequence;
+        packet.timestamp = self.clock.now();
+        // Handshake carriers and acks go out in the clear
+        if !matches!(
+            packet.packet_type,
+            PacketType::Probe | PacketType::Sync | PacketType::Handshake | PacketType::Ack
+        ) {
+            if let Some(session) = self.sessions.get(&packet.dst) {
+                session.seal(&mut packet);
+            }
+        }
+        packet.signature = self
+            .sign(packet.signing_bytes())
+            .as_ref()
+            .try_into()
+            .unwrap();
         self.network_log(format!(
             "Sending {:?}-0x{:X} to {:?}",
-            PacketType::from(packet.packet_type),
-            packet.packet_id,
-            packet.dst
+            packet.packet_type, packet.packet_id, packet.dst
         ));
 
-        if let Some(dst_addr) = self.addr_table.get(&packet.dst) {
-            self.socket.send_to(&packet.as_bytes(), dst_addr).unwrap();
-            if PacketType::from(packet.packet_type) != PacketType::Ack {
+        if let Some(dst_addr) = self.address(packet.dst).cloned() {
+            self.send_bytes(&packet, &dst_addr);
+            // Kept even if the send failed, so it is retried like a lost packet
+            if packet.packet_type != PacketType::Ack {
                 self.pending_acks
-                    .insert(packet.packet_id, (0, Instant::now(), packet.clone()));
+                    .insert(packet.packet_id, (0, self.clock.now(), packet));
             }
         }
     }
 
+    // Hands the packet to the transport, logging and counting a refused send rather
+    // than failing on it
+    fn send_bytes(&mut self, packet: &Packet, dst_addr: &str) {
+        if let Err(e) = self.transport.send_to(&packet.as_bytes(), dst_addr) {
+            self.failed_sends += 1;
+            self.network_log(format!(
+                "Failed to send {:?}-0x{:X} to {} at {}: {} ({} failed)",
+                packet.packet_type, packet.packet_id, packet.dst, dst_addr, e, self.failed_sends
+            ));
+        }
+    }
+
+    // Address of a peer, or else of a client that sent a request
+    fn address(&self, id: u16) -> Option<&String> {
+        self.addr_table
+            .get(&id)
+            .or_else(|| self.client_addrs.get(&id))
+    }
+
     fn check_ack_timeouts(&mut self) {
-        let now = Instant::now();
+        let now = self.clock.now();
         let mut to_retry: Vec<_> = self
             .pending_acks
             .iter()
             .filter(|(_, (_, sent_time, _))| {
-                now.duration_since(*sent_time) >= Duration::from_millis(ACK_TIMEOUT)
+                now.saturating_sub(*sent_time) >= self.config.ack_timeout
             })
             .map(|(&id, _)| id)
             .collect();
 
         for packet_id in to_retry.drain(..) {
             let (retries, _, packet) = self.pending_acks.remove(&packet_id).unwrap();
-            if retries < MAX_RETRIES {
-                if let Some(dst_addr) = self.addr_table.get(&packet.dst) {
+            // Lost pings are not resent, the next period sends a fresh one
+            if packet.packet_type == PacketType::Ping {
+                continue;
+            }
+            if retries < self.config.max_retries {
+                if let Some(dst_addr) = self.address(packet.dst).cloned() {
                     self.network_log(format!(
                         "*Packet* Retransmitting {:?}-0x{:X} (attempt {})",
-                        PacketType::from(packet.packet_type),
+                        packet.packet_type,
                         packet_id,
                         retries + 2
                     ));
-                    self.socket.send_to(&packet.as_bytes(), dst_addr).unwrap();
+                    self.send_bytes(&packet, &dst_addr);
                     self.pending_acks
-                        .insert(packet_id, (retries + 1, Instant::now(), packet));
+                        .insert(packet_id, (retries + 1, now, packet));
                 }
             } else {
                 self.system_log(format!(
-                    "*Packet* {:?}-0x{:X} failed after 3 retries",
-                    PacketType::from(packet.packet_type),
-                    packet_id
+                    "*Packet* {:?}-0x{:X} failed after {} retries",
+                    packet.packet_type, packet_id, retries
                 ));
             }
         }
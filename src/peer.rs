@@ -0,0 +1,84 @@
+use serde::Serialize;
+use std::fmt;
+
+pub const SUSPECT_TIMEOUT: u64 = 3000; // milliseconds
+pub const DEAD_TIMEOUT: u64 = 10000; // milliseconds
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    Alive,
+    // Silent for longer than the suspect timeout, still sent to as usual
+    Suspect,
+    // Silent for longer than the dead timeout, only pinged until it is heard from again
+    Dead,
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Alive => write!(f, "alive"),
+            Self::Suspect => write!(f, "suspect"),
+            Self::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+// Liveness of a peer node, judged by how long it has been since any authenticated
+// packet arrived from it
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    pub state: PeerState,
+    pub last_seen: u64, // milliseconds
+    // Smoothed round trip of packets the peer acknowledged on the first attempt
+    pub rtt: Option<u64>, // milliseconds
+}
+
+impl Peer {
+    pub fn new(now: u64) -> Self {
+        Self {
+            state: PeerState::Alive,
+            last_seen: now,
+            rtt: None,
+        }
+    }
+
+    // Returns true if the peer was suspect or dead
+    pub fn seen(&mut self, now: u64) -> bool {
+        self.last_seen = now;
+        let revived = self.state != PeerState::Alive;
+        self.state = PeerState::Alive;
+        revived
+    }
+
+    // Folds in a new sample with the 1/8 gain TCP uses for its smoothed RTT
+    pub fn record_rtt(&mut self, sample: u64) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    // Moves the peer to the state its silence calls for, returning the new state if
+    // it changed
+    pub fn update(
+        &mut self,
+        now: u64,
+        suspect_timeout: u64,
+        dead_timeout: u64,
+    ) -> Option<PeerState> {
+        let silence = now.saturating_sub(self.last_seen);
+        let state = if silence >= dead_timeout {
+            PeerState::Dead
+        } else if silence >= suspect_timeout {
+            PeerState::Suspect
+        } else {
+            PeerState::Alive
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
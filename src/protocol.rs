@@ -1,11 +1,143 @@
 use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, UnparsedPublicKey};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{block::Block, transaction::Transaction};
+use crate::{
+    block::{Block, MerkleProof, MerkleSibling},
+    clock::{Clock, SystemClock},
+    transaction::{ClientSignature, Transaction, CLIENT_SIGNATURE_SIZE},
+};
 
 pub const MAGIC_NUMBER: u32 = 0xA71A5001;
 pub const PACKET_BUFFER_SIZE: usize = 1024;
 pub const MAX_RETRIES: u8 = 3;
 pub const ACK_TIMEOUT: u64 = 500; // milliseconds
+pub const PACKET_HEADER_SIZE: usize = 94; // 30 bytes of fields and a 64-byte signature
+pub const AEAD_TAG_SIZE: usize = 16;
+// Leaves room for the authentication tag of an encrypted payload
+pub const MAX_PAYLOAD_SIZE: usize = PACKET_BUFFER_SIZE - PACKET_HEADER_SIZE - AEAD_TAG_SIZE;
+pub const FRAGMENT_HEADER_SIZE: usize = 9;
+pub const FRAGMENT_DATA_SIZE: usize = MAX_PAYLOAD_SIZE - FRAGMENT_HEADER_SIZE;
+pub const REASSEMBLY_TIMEOUT: u64 = 5000; // milliseconds
+pub const REPLAY_WINDOW: u64 = 1024; // sequence numbers tracked per sender
+pub const MAX_PACKET_AGE: u64 = 30000; // milliseconds
+pub const CHAIN_PAGE_SIZE: u32 = 32; // blocks per Chain response
+pub const CHAIN_PAGE_BYTES: usize = 1024 * 1024; // encoded blocks per Chain response
+
+// Messages reassembled at once, and the bytes reserved for them. The oldest message is
+// dropped to make room for a new one
+pub const MAX_PARTIAL_MESSAGES: usize = 64;
+pub const MAX_REASSEMBLY_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    Truncated { expected: usize, actual: usize },
+    BadMagic(u32),
+    UnknownPacketType(u8),
+    UnknownOperation(u8),
+    BadUtf8,
+    LengthMismatch { expected: usize, actual: usize },
+    InvalidSignature(u16),
+    KeyMismatch(u16),
+    Unauthenticated { src: u16, packet_type: PacketType },
+    Replayed { src: u16, sequence: u64 },
+    DecryptionFailed(u16),
+    // Data packet a peer sent outside its session
+    Unencrypted { src: u16, packet_type: PacketType },
+    // Payload naming another node than the sender, such as a vote cast for someone else
+    SenderMismatch { src: u16, claimed: u16 },
+    UnknownErrorCode(u8),
+    // Fragment count whose full size could never fit in `MAX_REASSEMBLY_BYTES`
+    MessageTooLarge(u16),
+    // Payload size too large to send, as its fragments could not be reassembled
+    PayloadTooLarge(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { expected, actual } => {
+                write!(f, "truncated: expected {} bytes, got {}", expected, actual)
+            }
+            Self::BadMagic(magic) => write!(f, "bad magic number 0x{:X}", magic),
+            Self::UnknownPacketType(value) => write!(f, "unknown packet type {}", value),
+            Self::UnknownOperation(value) => write!(f, "unknown cache operation {}", value),
+            Self::BadUtf8 => write!(f, "name is not valid UTF-8"),
+            Self::LengthMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "length mismatch: expected {} bytes, got {}",
+                    expected, actual
+                )
+            }
+            Self::InvalidSignature(src) => write!(f, "invalid packet signature from {}", src),
+            Self::KeyMismatch(src) => {
+                write!(f, "{} presented a key other than its pinned one", src)
+            }
+            Self::Unauthenticated { src, packet_type } => {
+                write!(f, "unauthenticated {:?} from {}", packet_type, src)
+            }
+            Self::Replayed { src, sequence } => {
+                write!(f, "stale or replayed packet #{} from {}", sequence, src)
+            }
+            Self::DecryptionFailed(src) => write!(f, "failed to decrypt packet from {}", src),
+            Self::Unencrypted { src, packet_type } => {
+                write!(f, "unencrypted {:?} from {}", packet_type, src)
+            }
+            Self::SenderMismatch { src, claimed } => {
+                write!(f, "packet from {} claims to be from {}", src, claimed)
+            }
+            Self::UnknownErrorCode(value) => write!(f, "unknown error code {}", value),
+            Self::MessageTooLarge(count) => {
+                write!(
+                    f,
+                    "message of {} fragments exceeds the reassembly limit",
+                    count
+                )
+            }
+            Self::PayloadTooLarge(size) => {
+                write!(f, "payload of {} bytes exceeds the reassembly limit", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+pub(crate) fn read_array<const N: usize>(
+    bytes: &[u8],
+    offset: usize,
+) -> Result<[u8; N], ProtocolError> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|slice| slice.try_into().ok())
+        .ok_or(ProtocolError::Truncated {
+            expected: offset + N,
+            actual: bytes.len(),
+        })
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ProtocolError> {
+    Ok(u16::from_le_bytes(read_array(bytes, offset)?))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ProtocolError> {
+    Ok(u32::from_le_bytes(read_array(bytes, offset)?))
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ProtocolError> {
+    Ok(u64::from_le_bytes(read_array(bytes, offset)?))
+}
+
+// Reads a zero-padded 64-byte name
+pub(crate) fn read_name(bytes: &[u8], offset: usize) -> Result<String, ProtocolError> {
+    let name_bytes: [u8; 64] = read_array(bytes, offset)?;
+    String::from_utf8(name_bytes.into_iter().take_while(|&b| b != 0).collect())
+        .map_err(|_| ProtocolError::BadUtf8)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PacketType {
@@ -19,11 +151,47 @@ pub enum PacketType {
     Block,
     Transaction,
     Ack,
+    Replicate,
+    GetProof,
+    Proof,
+    Heartbeat,
+    RequestVote,
+    Vote,
+    Fragment,
+    Handshake,
+    Error,
+    DeleteData,
+    ReplicateDelete,
+    // Sent by a node shutting down so peers stop sending to it. Carries no payload
+    Leave,
+    // Liveness probe between peers, answered by its ack. Carries no payload
+    Ping,
+    // Peers the sender knows, so the receiver can probe the ones it has not met
+    PeerList,
+    // Tells a client its SetData or DeleteData was applied
+    Confirm,
 }
 
-impl From<u8> for PacketType {
-    fn from(value: u8) -> Self {
-        match value {
+impl PacketType {
+    // Packets carrying cached values or chain contents, which peers only exchange
+    // over a session
+    pub fn carries_data(self) -> bool {
+        matches!(
+            self,
+            PacketType::Chain
+                | PacketType::Block
+                | PacketType::Transaction
+                | PacketType::Replicate
+                | PacketType::ReplicateDelete
+        )
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        Ok(match value {
             0 => PacketType::Probe,
             1 => PacketType::Sync,
             2 => PacketType::SetData,
@@ -34,8 +202,23 @@ impl From<u8> for PacketType {
             7 => PacketType::Block,
             8 => PacketType::Transaction,
             9 => PacketType::Ack,
-            _ => panic!("Invalid packet type"),
-        }
+            10 => PacketType::Replicate,
+            11 => PacketType::GetProof,
+            12 => PacketType::Proof,
+            13 => PacketType::Heartbeat,
+            14 => PacketType::RequestVote,
+            15 => PacketType::Vote,
+            16 => PacketType::Fragment,
+            17 => PacketType::Handshake,
+            18 => PacketType::Error,
+            19 => PacketType::DeleteData,
+            20 => PacketType::ReplicateDelete,
+            21 => PacketType::Leave,
+            22 => PacketType::Ping,
+            23 => PacketType::PeerList,
+            24 => PacketType::Confirm,
+            _ => return Err(ProtocolError::UnknownPacketType(value)),
+        })
     }
 }
 
@@ -45,8 +228,15 @@ pub struct Packet {
     pub packet_id: u32,
     pub src: u16,
     pub dst: u16,
-    pub packet_type: u8,
-    pub timestamp: u64,
+    pub packet_type: PacketType,
+    pub timestamp: u64, // milliseconds
+    // Per-sender counter, set when the packet is sent and kept on retransmission
+    pub sequence: u64,
+    // Whether the payload is sealed with the session key shared by src and dst
+    pub encrypted: bool,
+    // Sender's signature over the other header fields and the payload, all zero
+    // when unsigned
+    pub signature: [u8; 64],
     pub payload: Vec<u8>,
 }
 
@@ -59,57 +249,387 @@ impl Packet {
             packet_id: u32::from_le_bytes(random_bytes[0..4].try_into().unwrap()),
             src,
             dst,
-            packet_type: packet_type as u8,
-            timestamp: 0,
+            packet_type,
+            // Senders restamp packets from their own clock when they transmit them
+            timestamp: SystemClock.now(),
+            sequence: 0,
+            encrypted: false,
+            signature: [0; 64],
             payload,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() < 21 {
-            return None;
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() < PACKET_HEADER_SIZE {
+            return Err(ProtocolError::Truncated {
+                expected: PACKET_HEADER_SIZE,
+                actual: bytes.len(),
+            });
         }
-        let magic_number = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let magic_number = read_u32(bytes, 0)?;
         if magic_number != MAGIC_NUMBER {
-            return None;
-        }
-        let packet_id = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
-        let src = u16::from_le_bytes(bytes[8..10].try_into().unwrap());
-        let dst = u16::from_le_bytes(bytes[10..12].try_into().unwrap());
-        let packet_type = bytes[12];
-        let timestamp = u64::from_le_bytes(bytes[13..21].try_into().unwrap());
-        let payload = bytes[21..].to_vec();
-        Some(Self {
+            return Err(ProtocolError::BadMagic(magic_number));
+        }
+        let packet_id = read_u32(bytes, 4)?;
+        let src = read_u16(bytes, 8)?;
+        let dst = read_u16(bytes, 10)?;
+        let packet_type = PacketType::try_from(bytes[12])?;
+        let timestamp = read_u64(bytes, 13)?;
+        let sequence = read_u64(bytes, 21)?;
+        let [flags] = read_array(bytes, 29)?;
+        let signature = read_array(bytes, 30)?;
+        let payload = bytes[PACKET_HEADER_SIZE..].to_vec();
+        Ok(Self {
             magic_number: MAGIC_NUMBER,
             packet_id,
             src,
             dst,
             packet_type,
             timestamp,
+            sequence,
+            encrypted: flags & 1 != 0,
+            signature,
             payload,
         })
     }
+
     pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
+        bytes.extend_from_slice(&self.signature);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    // Header fields other than the signature
+    pub fn header_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.magic_number.to_le_bytes());
         bytes.extend_from_slice(&self.packet_id.to_le_bytes());
         bytes.extend_from_slice(&self.src.to_le_bytes());
         bytes.extend_from_slice(&self.dst.to_le_bytes());
-        bytes.extend_from_slice(&self.packet_type.to_le_bytes());
+        bytes.push(self.packet_type as u8);
         bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.push(self.encrypted as u8);
+        bytes
+    }
+
+    // Header fields and payload covered by the signature
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
         bytes.extend_from_slice(&self.payload);
         bytes
     }
 
-    pub fn clone(&self) -> Self {
+    pub fn is_signed(&self) -> bool {
+        self.signature != [0; 64]
+    }
+
+    pub fn verify_signature(&self, public_key: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&self.signing_bytes(), &self.signature)
+            .is_ok()
+    }
+
+    // Type of the packet this one carries, which for a fragment is that of the message
+    // it is part of
+    pub fn carried_type(&self) -> Result<PacketType, ProtocolError> {
+        if self.packet_type != PacketType::Fragment {
+            return Ok(self.packet_type);
+        }
+        PacketType::try_from(FragmentPayload::from_bytes(&self.payload)?.packet_type)
+    }
+
+    // Splits a packet whose payload exceeds one datagram into Fragment packets,
+    // each acknowledged on its own; the packet id becomes the message id. Fails for a
+    // payload too large for the receiver to reassemble
+    pub fn fragment(&self) -> Result<Vec<Packet>, ProtocolError> {
+        if self.payload.len() <= MAX_PAYLOAD_SIZE {
+            return Ok(vec![self.clone()]);
+        }
+        let chunks: Vec<&[u8]> = self.payload.chunks(FRAGMENT_DATA_SIZE).collect();
+        let too_large = ProtocolError::PayloadTooLarge(self.payload.len());
+        if chunks.len() * FRAGMENT_DATA_SIZE > MAX_REASSEMBLY_BYTES {
+            return Err(too_large);
+        }
+        let count = u16::try_from(chunks.len()).map_err(|_| too_large)?;
+        Ok(chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fragment = Packet::new(
+                    self.src,
+                    self.dst,
+                    PacketType::Fragment,
+                    FragmentPayload::new(
+                        self.packet_id,
+                        index as u16,
+                        count,
+                        self.packet_type as u8,
+                        chunk.to_vec(),
+                    )
+                    .as_bytes(),
+                );
+                fragment.timestamp = self.timestamp;
+                fragment
+            })
+            .collect())
+    }
+}
+
+// Starting point for a sender's sequence numbers. Taken from the clock so that a
+// restarted sender continues above the numbers it used before
+pub fn initial_sequence() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replay {
+    Fresh,
+    // Seen before, e.g. a retransmission whose ack was lost
+    Duplicate,
+    // Too old to tell apart from a replay
+    Stale,
+}
+
+#[derive(Debug, Default)]
+struct SequenceWindow {
+    highest: u64,
+    seen: HashSet<u64>,
+}
+
+// Sliding window over each sender's sequence numbers, rejecting packets whose
+// timestamp is more than `max_age` away from the local clock
+#[derive(Debug)]
+pub struct ReplayFilter {
+    window: u64,
+    max_age: u64,
+    senders: HashMap<u16, SequenceWindow>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReplayFilter {
+    pub fn new(window: u64, max_age: u64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            window,
+            max_age,
+            senders: HashMap::new(),
+            clock,
+        }
+    }
+
+    // Classifies the packet without remembering it, so anyone can be checked
+    pub fn check(&self, packet: &Packet) -> Replay {
+        if self.clock.now().abs_diff(packet.timestamp) > self.max_age {
+            return Replay::Stale;
+        }
+        let Some(sender) = self.senders.get(&packet.src) else {
+            return Replay::Fresh;
+        };
+        if packet.sequence.saturating_add(self.window) <= sender.highest {
+            return Replay::Stale;
+        }
+        if sender.seen.contains(&packet.sequence) {
+            return Replay::Duplicate;
+        }
+        Replay::Fresh
+    }
+
+    // Remembers a sequence number. Only called once the sender is authenticated, since
+    // a forged high sequence number would otherwise lock the real sender out
+    pub fn record(&mut self, src: u16, sequence: u64) {
+        let sender = self.senders.entry(src).or_default();
+        sender.seen.insert(sequence);
+        if sequence > sender.highest {
+            sender.highest = sequence;
+            let floor = sender.highest.saturating_sub(self.window);
+            sender.seen.retain(|&sequence| sequence > floor);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FragmentPayload {
+    pub message_id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub packet_type: u8,
+    pub data: Vec<u8>,
+}
+
+impl FragmentPayload {
+    pub fn new(message_id: u32, index: u16, count: u16, packet_type: u8, data: Vec<u8>) -> Self {
+        Self {
+            message_id,
+            index,
+            count,
+            packet_type,
+            data,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [packet_type] = read_array(bytes, 8)?;
+        Ok(Self {
+            message_id: read_u32(bytes, 0)?,
+            index: read_u16(bytes, 4)?,
+            count: read_u16(bytes, 6)?,
+            packet_type,
+            data: bytes[FRAGMENT_HEADER_SIZE..].to_vec(),
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(FRAGMENT_HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(&self.message_id.to_le_bytes());
+        bytes.extend_from_slice(&self.index.to_le_bytes());
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes.push(self.packet_type);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+#[derive(Debug)]
+struct PartialMessage {
+    packet: Packet,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: u64, // milliseconds
+}
+
+impl PartialMessage {
+    // Space held for the message, as if every fragment were full
+    fn reserved(&self) -> usize {
+        self.fragments.len() * FRAGMENT_DATA_SIZE
+    }
+}
+
+// Collects Fragment packets per sender until every piece of a message has arrived.
+// At most `MAX_PARTIAL_MESSAGES` are kept, reserving no more than
+// `MAX_REASSEMBLY_BYTES` between them
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<(u16, u32), PartialMessage>,
+    reserved: usize, // bytes
+    // Messages dropped to make room since the last `expire`
+    evicted: usize,
+    timeout: u64, // milliseconds
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(REASSEMBLY_TIMEOUT, Arc::new(SystemClock))
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: u64, clock: Arc<dyn Clock>) -> Self {
         Self {
-            magic_number: self.magic_number,
-            packet_id: self.packet_id,
-            src: self.src,
-            dst: self.dst,
-            packet_type: self.packet_type,
-            timestamp: self.timestamp,
-            payload: self.payload.clone(),
+            partial: HashMap::new(),
+            reserved: 0,
+            evicted: 0,
+            timeout,
+            clock,
+        }
+    }
+
+    // Returns the original packet once its last missing fragment arrives
+    pub fn insert(&mut self, packet: &Packet) -> Result<Option<Packet>, ProtocolError> {
+        let fragment = FragmentPayload::from_bytes(&packet.payload)?;
+        let packet_type = PacketType::try_from(fragment.packet_type)?;
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return Err(ProtocolError::LengthMismatch {
+                expected: fragment.count as usize,
+                actual: fragment.index as usize,
+            });
+        }
+        let key = (packet.src, fragment.message_id);
+        if !self.partial.contains_key(&key) {
+            let reserved = fragment.count as usize * FRAGMENT_DATA_SIZE;
+            if reserved > MAX_REASSEMBLY_BYTES {
+                return Err(ProtocolError::MessageTooLarge(fragment.count));
+            }
+            while self.partial.len() >= MAX_PARTIAL_MESSAGES
+                || self.reserved + reserved > MAX_REASSEMBLY_BYTES
+            {
+                self.evict_oldest();
+            }
+            self.reserved += reserved;
+        }
+        let message = self.partial.entry(key).or_insert_with(|| PartialMessage {
+            packet: Packet {
+                magic_number: MAGIC_NUMBER,
+                packet_id: fragment.message_id,
+                src: packet.src,
+                dst: packet.dst,
+                packet_type,
+                timestamp: packet.timestamp,
+                sequence: packet.sequence,
+                // Fragments are decrypted and authenticated one by one
+                encrypted: false,
+                signature: [0; 64],
+                payload: Vec::new(),
+            },
+            fragments: vec![None; fragment.count as usize],
+            received: 0,
+            started: self.clock.now(),
+        });
+        if message.fragments.len() != fragment.count as usize {
+            return Err(ProtocolError::LengthMismatch {
+                expected: message.fragments.len(),
+                actual: fragment.count as usize,
+            });
+        }
+        let slot = &mut message.fragments[fragment.index as usize];
+        if slot.is_none() {
+            *slot = Some(fragment.data);
+            message.received += 1;
+        }
+        if message.received < message.fragments.len() {
+            return Ok(None);
+        }
+
+        let Some(mut message) = self.partial.remove(&key) else {
+            return Ok(None);
+        };
+        self.reserved -= message.reserved();
+        message.packet.payload = message.fragments.into_iter().flatten().flatten().collect();
+        Ok(Some(message.packet))
+    }
+
+    // Drops messages whose fragments stopped arriving. Returns how many were dropped,
+    // counting those evicted to make room since the last call
+    pub fn expire(&mut self) -> usize {
+        let before = self.partial.len();
+        let timeout = self.timeout;
+        let now = self.clock.now();
+        let mut released = 0;
+        self.partial.retain(|_, message| {
+            let keep = now.saturating_sub(message.started) < timeout;
+            if !keep {
+                released += message.reserved();
+            }
+            keep
+        });
+        self.reserved -= released;
+        before - self.partial.len() + std::mem::take(&mut self.evicted)
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some(key) = self
+            .partial
+            .iter()
+            .min_by_key(|(_, message)| message.started)
+            .map(|(&key, _)| key)
+        else {
+            return;
+        };
+        if let Some(message) = self.partial.remove(&key) {
+            self.reserved -= message.reserved();
+            self.evicted += 1;
         }
     }
 }
@@ -123,10 +643,10 @@ impl AckPayload {
     pub fn new(packet_id: u32) -> Self {
         Self { packet_id }
     }
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        Self {
-            packet_id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
-        }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            packet_id: read_u32(bytes, 0)?,
+        })
     }
     pub fn as_bytes(&self) -> Vec<u8> {
         self.packet_id.to_le_bytes().to_vec()
@@ -137,27 +657,32 @@ impl AckPayload {
 pub struct ProbePayload {
     pub node_id: u16,
     pub public_key: [u8; 32],
+    // X25519 key opening a session handshake
+    pub ephemeral_key: [u8; 32],
 }
 
 impl ProbePayload {
-    pub fn new(node_id: u16, public_key: [u8; 32]) -> Self {
+    pub fn new(node_id: u16, public_key: [u8; 32], ephemeral_key: [u8; 32]) -> Self {
         Self {
             node_id,
             public_key,
+            ephemeral_key,
         }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        Self {
-            node_id: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
-            public_key: bytes[2..34].try_into().unwrap(),
-        }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            node_id: read_u16(bytes, 0)?,
+            public_key: read_array(bytes, 2)?,
+            ephemeral_key: read_array(bytes, 34)?,
+        })
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.node_id.to_le_bytes());
         bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.ephemeral_key);
         bytes
     }
 }
@@ -169,6 +694,8 @@ pub struct SyncPayload {
     pub chain_height: u32,
     pub last_block_hash: [u8; 32],
     pub last_block_timestamp: u64,
+    // X25519 key answering the handshake opened by a Probe, all zero when declined
+    pub ephemeral_key: [u8; 32],
 }
 
 impl SyncPayload {
@@ -178,6 +705,7 @@ impl SyncPayload {
         chain_height: u32,
         last_block_hash: [u8; 32],
         last_block_timestamp: u64,
+        ephemeral_key: [u8; 32],
     ) -> Self {
         Self {
             node_id,
@@ -185,16 +713,18 @@ impl SyncPayload {
             chain_height,
             last_block_hash,
             last_block_timestamp,
+            ephemeral_key,
         }
     }
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        Self {
-            node_id: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
-            public_key: bytes[2..34].try_into().unwrap(),
-            chain_height: u32::from_le_bytes(bytes[34..38].try_into().unwrap()),
-            last_block_hash: bytes[38..70].try_into().unwrap(),
-            last_block_timestamp: u64::from_le_bytes(bytes[70..78].try_into().unwrap()),
-        }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            node_id: read_u16(bytes, 0)?,
+            public_key: read_array(bytes, 2)?,
+            chain_height: read_u32(bytes, 34)?,
+            last_block_hash: read_array(bytes, 38)?,
+            last_block_timestamp: read_u64(bytes, 70)?,
+            ephemeral_key: read_array(bytes, 78)?,
+        })
     }
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -203,6 +733,7 @@ impl SyncPayload {
         bytes.extend_from_slice(&self.chain_height.to_le_bytes());
         bytes.extend_from_slice(&self.last_block_hash);
         bytes.extend_from_slice(&self.last_block_timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.ephemeral_key);
         bytes
     }
 }
@@ -219,19 +750,12 @@ impl DataPayload {
         Self { name, data }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let name = String::from_utf8(
-            bytes[0..64]
-                .to_vec()
-                .into_iter()
-                .take_while(|&b| b != 0)
-                .collect(),
-        )
-        .unwrap();
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let name = read_name(bytes, 0)?;
 
         let data = bytes[64..].to_vec();
 
-        Self { name, data }
+        Ok(Self { name, data })
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -245,38 +769,110 @@ impl DataPayload {
     }
 }
 
+// A write or delete shared with peers, versioned by the transaction that records it.
+// Deletes carry no data
 #[derive(Debug, Clone)]
-pub struct TransactionPayload {
-    pub transaction: Transaction,
-    pub signature: [u8; 64],
+pub struct ReplicatePayload {
+    pub name: String, // max 64 bytes
+    pub timestamp: u64,
+    pub transaction_hash: [u8; 32],
+    pub data: Vec<u8>,
 }
 
-impl TransactionPayload {
-    pub fn new(transaction: Transaction, signature: [u8; 64]) -> Self {
+impl ReplicatePayload {
+    pub fn new(name: String, timestamp: u64, transaction_hash: [u8; 32], data: Vec<u8>) -> Self {
+        assert!(name.len() <= 64, "Name must not exceed 64 bytes");
         Self {
-            transaction,
-            signature,
+            name,
+            timestamp,
+            transaction_hash,
+            data,
         }
     }
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(
-            bytes.len() == 173,
-            "Invalid transaction payload length, expected 173, got {:?}",
-            bytes.len()
-        );
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            name: read_name(bytes, 0)?,
+            timestamp: read_u64(bytes, 64)?,
+            transaction_hash: read_array(bytes, 72)?,
+            data: bytes[104..].to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(104 + self.data.len());
+
+        let mut name_bytes = [0u8; 64];
+        name_bytes[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        bytes.extend_from_slice(&name_bytes);
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.transaction_hash);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+// A client request for a data name, signed by the client. Writes carry the value
+#[derive(Debug, Clone)]
+pub struct RequestPayload {
+    pub name: String, // max 64 bytes
+    pub client_signature: ClientSignature,
+    pub data: Vec<u8>,
+}
+
+impl RequestPayload {
+    pub fn new(name: String, client_signature: ClientSignature, data: Vec<u8>) -> Self {
+        assert!(name.len() <= 64, "Name must not exceed 64 bytes");
         Self {
-            transaction: Transaction::from_bytes(&bytes[0..109]),
-            signature: bytes[109..173].try_into().unwrap(),
+            name,
+            client_signature,
+            data,
         }
     }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let name = read_name(bytes, 0)?;
+        let client_signature = ClientSignature::from_bytes(&bytes[64..])?;
+        let data = bytes[64 + CLIENT_SIGNATURE_SIZE..].to_vec();
+
+        Ok(Self {
+            name,
+            client_signature,
+            data,
+        })
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.transaction.as_bytes());
-        bytes.extend_from_slice(&self.signature);
+        let mut bytes = Vec::with_capacity(64 + CLIENT_SIGNATURE_SIZE + self.data.len());
+
+        let mut name_bytes = [0u8; 64];
+        name_bytes[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        bytes.extend_from_slice(&name_bytes);
+        bytes.extend_from_slice(&self.client_signature.as_bytes());
+        bytes.extend_from_slice(&self.data);
         bytes
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct TransactionPayload {
+    pub transaction: Transaction,
+}
+
+impl TransactionPayload {
+    pub fn new(transaction: Transaction) -> Self {
+        Self { transaction }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            transaction: Transaction::from_bytes(bytes)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.transaction.as_bytes()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockPayload {
     pub block: Block,
@@ -286,53 +882,848 @@ impl BlockPayload {
     pub fn new(block: Block) -> Self {
         Self { block }
     }
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            block: Block::from_bytes(bytes)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.block.as_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetChainPayload {
+    // Height of the first missing block, i.e. the requester's chain length
+    pub from_height: u32,
+    // Hash of the requester's last block, zero for an empty chain
+    pub tip_hash: [u8; 32],
+    pub max_blocks: u32,
+}
+
+impl GetChainPayload {
+    pub fn new(from_height: u32, tip_hash: [u8; 32], max_blocks: u32) -> Self {
         Self {
-            block: Block::from_bytes(&bytes),
+            from_height,
+            tip_hash,
+            max_blocks,
         }
     }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            from_height: read_u32(bytes, 0)?,
+            tip_hash: read_array(bytes, 4)?,
+            max_blocks: read_u32(bytes, 36)?,
+        })
+    }
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.block.as_bytes());
+        bytes.extend_from_slice(&self.from_height.to_le_bytes());
+        bytes.extend_from_slice(&self.tip_hash);
+        bytes.extend_from_slice(&self.max_blocks.to_le_bytes());
         bytes
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ChainPayload {
+    // Height of the first block in `chain`
+    pub start_height: u32,
+    // Full height of the sender's chain, so the receiver knows whether to ask for more
+    pub chain_height: u32,
     pub chain: Vec<Block>,
 }
 
 impl ChainPayload {
-    pub fn new(chain: Vec<Block>) -> Self {
-        Self { chain }
+    pub fn new(start_height: u32, chain_height: u32, chain: Vec<Block>) -> Self {
+        Self {
+            start_height,
+            chain_height,
+            chain,
+        }
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let start_height = read_u32(bytes, 0)?;
+        let chain_height = read_u32(bytes, 4)?;
         let mut blocks = Vec::new();
-        let mut offset = 0;
+        let mut offset = 8;
 
-        while offset + 2 <= bytes.len() {
-            let size = u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap()) as usize;
-            if offset + 2 + size > bytes.len() {
-                break;
-            }
-            let block = Block::from_bytes(&bytes[offset + 2..offset + 2 + size]);
-            blocks.push(block);
+        while offset < bytes.len() {
+            let size = read_u32(bytes, offset)? as usize;
+            let Some(block_bytes) = bytes.get(offset + 4..offset + 4 + size) else {
+                return Err(ProtocolError::Truncated {
+                    expected: offset + 4 + size,
+                    actual: bytes.len(),
+                });
+            };
+            blocks.push(Block::from_bytes(block_bytes)?);
 
-            offset += 2 + size;
+            offset += 4 + size;
         }
 
-        Self { chain: blocks }
+        Ok(Self {
+            start_height,
+            chain_height,
+            chain: blocks,
+        })
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.start_height.to_le_bytes());
+        bytes.extend_from_slice(&self.chain_height.to_le_bytes());
         for block in &self.chain {
             let block_bytes = block.as_bytes();
-            bytes.extend_from_slice(&(block_bytes.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
             bytes.extend_from_slice(&block_bytes);
         }
         bytes
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct GetProofPayload {
+    pub transaction_hash: [u8; 32],
+}
+
+impl GetProofPayload {
+    pub fn new(transaction_hash: [u8; 32]) -> Self {
+        Self { transaction_hash }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            transaction_hash: read_array(bytes, 0)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.transaction_hash.to_vec()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProofPayload {
+    pub block_height: u32,
+    pub merkle_root: [u8; 32],
+    pub proof: MerkleProof,
+}
+
+impl ProofPayload {
+    pub fn new(block_height: u32, merkle_root: [u8; 32], proof: MerkleProof) -> Self {
+        Self {
+            block_height,
+            merkle_root,
+            proof,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let block_height = read_u32(bytes, 0)?;
+        let merkle_root = read_array(bytes, 4)?;
+        let transaction_hash = read_array(bytes, 36)?;
+        let siblings = bytes[68..]
+            .chunks(33)
+            .map(|sibling| {
+                let [left] = read_array(sibling, 0)?;
+                Ok(MerkleSibling {
+                    left: left != 0,
+                    hash: read_array(sibling, 1)?,
+                })
+            })
+            .collect::<Result<_, ProtocolError>>()?;
+
+        Ok(Self {
+            block_height,
+            merkle_root,
+            proof: MerkleProof {
+                transaction_hash,
+                siblings,
+            },
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.block_height.to_le_bytes());
+        bytes.extend_from_slice(&self.merkle_root);
+        bytes.extend_from_slice(&self.proof.transaction_hash);
+        for sibling in &self.proof.siblings {
+            bytes.push(sibling.left as u8);
+            bytes.extend_from_slice(&sibling.hash);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatPayload {
+    pub node_id: u16,
+    pub term: u32,
+    pub leader: u16,
+    pub chain_height: u32,
+    // Blocks a majority holds, and the hash of the last of them
+    pub commit_height: u32,
+    pub commit_hash: [u8; 32],
+}
+
+impl HeartbeatPayload {
+    pub fn new(
+        node_id: u16,
+        term: u32,
+        leader: u16,
+        chain_height: u32,
+        commit_height: u32,
+        commit_hash: [u8; 32],
+    ) -> Self {
+        Self {
+            node_id,
+            term,
+            leader,
+            chain_height,
+            commit_height,
+            commit_hash,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            node_id: read_u16(bytes, 0)?,
+            term: read_u32(bytes, 2)?,
+            leader: read_u16(bytes, 6)?,
+            chain_height: read_u32(bytes, 8)?,
+            commit_height: read_u32(bytes, 12)?,
+            commit_hash: read_array(bytes, 16)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.node_id.to_le_bytes());
+        bytes.extend_from_slice(&self.term.to_le_bytes());
+        bytes.extend_from_slice(&self.leader.to_le_bytes());
+        bytes.extend_from_slice(&self.chain_height.to_le_bytes());
+        bytes.extend_from_slice(&self.commit_height.to_le_bytes());
+        bytes.extend_from_slice(&self.commit_hash);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestVotePayload {
+    pub candidate: u16,
+    pub term: u32,
+    pub chain_height: u32,
+}
+
+impl RequestVotePayload {
+    pub fn new(candidate: u16, term: u32, chain_height: u32) -> Self {
+        Self {
+            candidate,
+            term,
+            chain_height,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            candidate: read_u16(bytes, 0)?,
+            term: read_u32(bytes, 2)?,
+            chain_height: read_u32(bytes, 6)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.candidate.to_le_bytes());
+        bytes.extend_from_slice(&self.term.to_le_bytes());
+        bytes.extend_from_slice(&self.chain_height.to_le_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VotePayload {
+    pub node_id: u16,
+    pub term: u32,
+    pub granted: bool,
+}
+
+impl VotePayload {
+    pub fn new(node_id: u16, term: u32, granted: bool) -> Self {
+        Self {
+            node_id,
+            term,
+            granted,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [granted] = read_array(bytes, 6)?;
+        Ok(Self {
+            node_id: read_u16(bytes, 0)?,
+            term: read_u32(bytes, 2)?,
+            granted: granted != 0,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.node_id.to_le_bytes());
+        bytes.extend_from_slice(&self.term.to_le_bytes());
+        bytes.push(self.granted as u8);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakePayload {
+    pub ephemeral_key: [u8; 32],
+    // Whether this answers a handshake rather than opening one
+    pub reply: bool,
+    // Identity key of the sender, which signs the packet
+    pub public_key: [u8; 32],
+}
+
+impl HandshakePayload {
+    pub fn new(ephemeral_key: [u8; 32], reply: bool, public_key: [u8; 32]) -> Self {
+        Self {
+            ephemeral_key,
+            reply,
+            public_key,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [reply] = read_array(bytes, 32)?;
+        Ok(Self {
+            ephemeral_key: read_array(bytes, 0)?,
+            reply: reply != 0,
+            public_key: read_array(bytes, 33)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.ephemeral_key.to_vec();
+        bytes.push(self.reply as u8);
+        bytes.extend_from_slice(&self.public_key);
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    AccessDenied,
+    UnknownClient,
+    InvalidClientSignature,
+    // Signed too long ago or for another node, or already seen
+    StaleRequest,
+    // The node could not write the change to its cache
+    StorageFailed,
+    // Nothing matches the request, such as a proof for a transaction in no block
+    NotFound,
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => ErrorCode::AccessDenied,
+            1 => ErrorCode::UnknownClient,
+            2 => ErrorCode::InvalidClientSignature,
+            3 => ErrorCode::StaleRequest,
+            4 => ErrorCode::StorageFailed,
+            5 => ErrorCode::NotFound,
+            _ => return Err(ProtocolError::UnknownErrorCode(value)),
+        })
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AccessDenied => write!(f, "access denied"),
+            Self::UnknownClient => write!(f, "client key not accepted"),
+            Self::InvalidClientSignature => write!(f, "invalid client signature"),
+            Self::StaleRequest => write!(f, "stale, replayed or misaddressed request"),
+            Self::StorageFailed => write!(f, "node failed to store the change"),
+            Self::NotFound => write!(f, "not found"),
+        }
+    }
+}
+
+// Tells a client why its request was refused. `request_id` is the id of the request packet
+#[derive(Debug, Clone)]
+pub struct ErrorPayload {
+    pub request_id: u32,
+    pub code: ErrorCode,
+    pub name: String, // max 64 bytes
+}
+
+impl ErrorPayload {
+    pub fn new(request_id: u32, code: ErrorCode, name: String) -> Self {
+        assert!(name.len() <= 64, "Name must not exceed 64 bytes");
+        Self {
+            request_id,
+            code,
+            name,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [code] = read_array(bytes, 4)?;
+        Ok(Self {
+            request_id: read_u32(bytes, 0)?,
+            code: ErrorCode::try_from(code)?,
+            name: read_name(bytes, 5)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.request_id.to_le_bytes().to_vec();
+        bytes.push(self.code as u8);
+        let mut name_bytes = [0u8; 64];
+        name_bytes[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        bytes.extend_from_slice(&name_bytes);
+        bytes
+    }
+}
+
+impl fmt::Display for ErrorPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} for {:?}", self.code, self.name)
+    }
+}
+
+// Tells a client its request was applied and which transaction records it.
+// `request_id` is the id of the request packet
+#[derive(Debug, Clone)]
+pub struct ConfirmPayload {
+    pub request_id: u32,
+    pub transaction_hash: [u8; 32],
+}
+
+impl ConfirmPayload {
+    pub fn new(request_id: u32, transaction_hash: [u8; 32]) -> Self {
+        Self {
+            request_id,
+            transaction_hash,
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            request_id: read_u32(bytes, 0)?,
+            transaction_hash: read_array(bytes, 4)?,
+        })
+    }
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.request_id.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.transaction_hash);
+        bytes
+    }
+}
+
+const PEER_ENTRY_SIZE: usize = 98;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerEntry {
+    pub id: u16,
+    pub public_key: [u8; 32],
+    pub address: String, // max 64 bytes
+}
+
+// Node ids, keys and addresses gossiped between peers, and announced over multicast
+// with the sender as the only entry
+#[derive(Debug, Clone)]
+pub struct PeerListPayload {
+    pub peers: Vec<PeerEntry>,
+}
+
+impl PeerListPayload {
+    pub fn new(peers: Vec<PeerEntry>) -> Self {
+        assert!(
+            peers.iter().all(|peer| peer.address.len() <= 64),
+            "Address must not exceed 64 bytes"
+        );
+        Self { peers }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if !bytes.len().is_multiple_of(PEER_ENTRY_SIZE) {
+            return Err(ProtocolError::LengthMismatch {
+                expected: bytes.len().next_multiple_of(PEER_ENTRY_SIZE),
+                actual: bytes.len(),
+            });
+        }
+        let peers = (0..bytes.len())
+            .step_by(PEER_ENTRY_SIZE)
+            .map(|offset| {
+                Ok(PeerEntry {
+                    id: read_u16(bytes, offset)?,
+                    public_key: read_array(bytes, offset + 2)?,
+                    address: read_name(bytes, offset + 34)?,
+                })
+            })
+            .collect::<Result<_, ProtocolError>>()?;
+        Ok(Self { peers })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.peers.len() * PEER_ENTRY_SIZE);
+        for peer in &self.peers {
+            bytes.extend_from_slice(&peer.id.to_le_bytes());
+            bytes.extend_from_slice(&peer.public_key);
+            let mut address_bytes = [0u8; 64];
+            address_bytes[..peer.address.len()].copy_from_slice(peer.address.as_bytes());
+            bytes.extend_from_slice(&address_bytes);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BLOCK_HEADER_SIZE;
+    use crate::cache::CacheOperation;
+    use crate::clock::VirtualClock;
+    use std::time::Duration;
+
+    // Checks that `decode` accepts `bytes` but fails, without panicking, on every
+    // prefix shorter than `min_len`
+    fn assert_prefixes_fail<T>(
+        bytes: &[u8],
+        min_len: usize,
+        decode: fn(&[u8]) -> Result<T, ProtocolError>,
+    ) {
+        assert!(decode(bytes).is_ok());
+        for len in 0..min_len {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {} bytes", len);
+        }
+    }
+
+    fn client_signature() -> ClientSignature {
+        ClientSignature {
+            public_key: [1; 32],
+            node_id: 1,
+            timestamp: 1000,
+            nonce: [2; 16],
+            signature: [3; 64],
+        }
+    }
+
+    fn transaction() -> Transaction {
+        Transaction::new(
+            &VirtualClock::new(5000),
+            1,
+            2,
+            "name".to_string(),
+            CacheOperation::Set,
+            [4; 32],
+            [0; 32],
+            client_signature(),
+        )
+    }
+
+    fn block() -> Block {
+        Block::new(
+            &VirtualClock::new(5000),
+            vec![transaction(), transaction()],
+            [5; 32],
+            1,
+        )
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packet = Packet::new(1, 2, PacketType::Ping, vec![1, 2, 3]);
+        assert_prefixes_fail(&packet.as_bytes(), PACKET_HEADER_SIZE, Packet::from_bytes);
+        assert!(matches!(
+            Packet::from_bytes(&packet.as_bytes()[..10]),
+            Err(ProtocolError::Truncated {
+                expected: PACKET_HEADER_SIZE,
+                actual: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn packets_with_a_bad_header_are_rejected() {
+        let mut bytes = Packet::new(1, 2, PacketType::Ping, Vec::new()).as_bytes();
+        bytes[12] = 0xFF;
+        assert!(matches!(
+            Packet::from_bytes(&bytes),
+            Err(ProtocolError::UnknownPacketType(0xFF))
+        ));
+        bytes[0] ^= 1;
+        assert!(matches!(
+            Packet::from_bytes(&bytes),
+            Err(ProtocolError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_fixed_size_payloads_are_rejected() {
+        let bytes = HeartbeatPayload::new(1, 2, 1, 3, 3, [6; 32]).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), HeartbeatPayload::from_bytes);
+        let bytes = RequestVotePayload::new(1, 2, 3).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), RequestVotePayload::from_bytes);
+        let bytes = VotePayload::new(1, 2, true).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), VotePayload::from_bytes);
+        let bytes = HandshakePayload::new([1; 32], false, [2; 32]).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), HandshakePayload::from_bytes);
+        let bytes = GetChainPayload::new(1, [2; 32], CHAIN_PAGE_SIZE).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), GetChainPayload::from_bytes);
+        let bytes = ErrorPayload::new(1, ErrorCode::NotFound, "name".to_string()).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), ErrorPayload::from_bytes);
+        let bytes = ConfirmPayload::new(1, [2; 32]).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), ConfirmPayload::from_bytes);
+        let bytes = TransactionPayload::new(transaction()).as_bytes();
+        assert_prefixes_fail(&bytes, bytes.len(), TransactionPayload::from_bytes);
+    }
+
+    #[test]
+    fn truncated_payload_headers_are_rejected() {
+        let bytes = FragmentPayload::new(1, 0, 2, PacketType::Chain as u8, vec![7; 10]).as_bytes();
+        assert_prefixes_fail(&bytes, FRAGMENT_HEADER_SIZE, FragmentPayload::from_bytes);
+        let bytes = DataPayload::new("name".to_string(), vec![7; 10]).as_bytes();
+        assert_prefixes_fail(&bytes, 64, DataPayload::from_bytes);
+        let bytes = ReplicatePayload::new("name".to_string(), 1, [2; 32], vec![7; 10]).as_bytes();
+        assert_prefixes_fail(&bytes, 104, ReplicatePayload::from_bytes);
+        let bytes =
+            RequestPayload::new("name".to_string(), client_signature(), vec![7; 10]).as_bytes();
+        assert_prefixes_fail(
+            &bytes,
+            64 + CLIENT_SIGNATURE_SIZE,
+            RequestPayload::from_bytes,
+        );
+    }
+
+    #[test]
+    fn truncated_blocks_and_chains_are_rejected() {
+        // Shorter payloads that end on a block, transaction or sibling are whole ones
+        let bytes = BlockPayload::new(block()).as_bytes();
+        assert_prefixes_fail(&bytes, BLOCK_HEADER_SIZE, BlockPayload::from_bytes);
+        assert!(BlockPayload::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let bytes = ChainPayload::new(0, 2, vec![block(), block()]).as_bytes();
+        assert_prefixes_fail(&bytes, 8, ChainPayload::from_bytes);
+        assert!(ChainPayload::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let block = block();
+        let proof = block.merkle_proof(&block.transactions[0].hash).unwrap();
+        let bytes = ProofPayload::new(0, block.merkle_root, proof).as_bytes();
+        assert_prefixes_fail(&bytes, 68, ProofPayload::from_bytes);
+        assert!(ProofPayload::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn a_chain_block_length_past_the_end_is_rejected() {
+        let mut bytes = ChainPayload::new(0, 1, vec![block()]).as_bytes();
+        bytes[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ChainPayload::from_bytes(&bytes),
+            Err(ProtocolError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_field_values_are_rejected() {
+        let mut bytes = ErrorPayload::new(1, ErrorCode::NotFound, "name".to_string()).as_bytes();
+        bytes[4] = 0xFF;
+        assert!(matches!(
+            ErrorPayload::from_bytes(&bytes),
+            Err(ProtocolError::UnknownErrorCode(0xFF))
+        ));
+
+        let mut bytes = DataPayload::new("name".to_string(), Vec::new()).as_bytes();
+        bytes[0] = 0xFF;
+        assert!(matches!(
+            DataPayload::from_bytes(&bytes),
+            Err(ProtocolError::BadUtf8)
+        ));
+
+        let mut bytes = transaction().as_bytes();
+        bytes[68] = 0xFF;
+        assert!(matches!(
+            Transaction::from_bytes(&bytes),
+            Err(ProtocolError::UnknownOperation(0xFF))
+        ));
+
+        let mut bytes = BlockPayload::new(block()).as_bytes();
+        bytes.push(0);
+        assert!(matches!(
+            BlockPayload::from_bytes(&bytes),
+            Err(ProtocolError::LengthMismatch { .. })
+        ));
+
+        let entry = PeerEntry {
+            id: 2,
+            public_key: [1; 32],
+            address: "127.0.0.1:7002".to_string(),
+        };
+        let bytes = PeerListPayload::new(vec![entry]).as_bytes();
+        assert!(matches!(
+            PeerListPayload::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProtocolError::LengthMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn fragment_splits_a_large_payload() {
+        let packet = Packet::new(1, 2, PacketType::Chain, vec![7; FRAGMENT_DATA_SIZE * 3 + 1]);
+        let fragments = packet.fragment().unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments
+            .iter()
+            .all(|fragment| fragment.packet_type == PacketType::Fragment));
+    }
+
+    #[test]
+    fn fragment_refuses_a_payload_too_large_to_reassemble() {
+        let size = MAX_REASSEMBLY_BYTES + 1;
+        let packet = Packet::new(1, 2, PacketType::Chain, vec![0; size]);
+        assert!(matches!(
+            packet.fragment(),
+            Err(ProtocolError::PayloadTooLarge(actual)) if actual == size
+        ));
+    }
+
+    fn packet_at(src: u16, sequence: u64, timestamp: u64) -> Packet {
+        let mut packet = Packet::new(src, 1, PacketType::Ping, Vec::new());
+        packet.sequence = sequence;
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    fn fragment_packet(src: u16, message_id: u32, index: u16, count: u16) -> Packet {
+        let fragment = FragmentPayload::new(
+            message_id,
+            index,
+            count,
+            PacketType::Chain as u8,
+            vec![7; 4],
+        );
+        Packet::new(src, 1, PacketType::Fragment, fragment.as_bytes())
+    }
+
+    #[test]
+    fn replay_filter_only_remembers_recorded_sequences() {
+        let clock = VirtualClock::new(10_000);
+        let mut filter = ReplayFilter::new(REPLAY_WINDOW, MAX_PACKET_AGE, Arc::new(clock));
+        let packet = packet_at(2, 1, 10_000);
+        assert_eq!(filter.check(&packet), Replay::Fresh);
+        assert_eq!(filter.check(&packet), Replay::Fresh);
+
+        filter.record(2, 1);
+        assert_eq!(filter.check(&packet), Replay::Duplicate);
+        // Each sender has its own window
+        assert_eq!(filter.check(&packet_at(3, 1, 10_000)), Replay::Fresh);
+    }
+
+    #[test]
+    fn replay_filter_rejects_sequences_below_the_window_and_old_timestamps() {
+        let clock = VirtualClock::new(100_000);
+        let mut filter = ReplayFilter::new(16, 1000, Arc::new(clock.clone()));
+        filter.record(2, 1);
+        filter.record(2, 20);
+        // Numbers below the window are forgotten, and refused even if never seen
+        assert_eq!(filter.senders[&2].seen.len(), 1);
+        assert_eq!(filter.check(&packet_at(2, 1, 100_000)), Replay::Stale);
+        assert_eq!(filter.check(&packet_at(2, 3, 100_000)), Replay::Stale);
+        assert_eq!(filter.check(&packet_at(2, 5, 100_000)), Replay::Fresh);
+        assert_eq!(filter.check(&packet_at(2, 21, 100_000)), Replay::Fresh);
+
+        assert_eq!(filter.check(&packet_at(2, 21, 98_999)), Replay::Stale);
+        assert_eq!(filter.check(&packet_at(2, 21, 101_001)), Replay::Stale);
+        clock.advance(Duration::from_millis(1001));
+        assert_eq!(filter.check(&packet_at(2, 21, 100_000)), Replay::Stale);
+    }
+
+    #[test]
+    fn reassembler_rebuilds_fragments_in_any_order() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT, Arc::new(VirtualClock::new(0)));
+        let packet = Packet::new(
+            2,
+            1,
+            PacketType::Chain,
+            (0..3000).map(|i| i as u8).collect(),
+        );
+        let mut fragments = packet.fragment().unwrap();
+        let last = fragments.remove(0);
+        for fragment in fragments.iter().rev() {
+            assert!(reassembler.insert(fragment).unwrap().is_none());
+        }
+        // A repeated fragment changes nothing
+        assert!(reassembler.insert(&fragments[0]).unwrap().is_none());
+
+        let rebuilt = reassembler.insert(&last).unwrap().unwrap();
+        assert_eq!(rebuilt.packet_type, PacketType::Chain);
+        assert_eq!(rebuilt.packet_id, packet.packet_id);
+        assert_eq!(rebuilt.payload, packet.payload);
+        assert_eq!(reassembler.reserved, 0);
+    }
+
+    #[test]
+    fn reassembler_evicts_the_oldest_message_beyond_the_message_limit() {
+        let clock = VirtualClock::new(0);
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT, Arc::new(clock.clone()));
+        for message_id in 0..=MAX_PARTIAL_MESSAGES as u32 {
+            clock.advance(Duration::from_millis(1));
+            assert!(reassembler
+                .insert(&fragment_packet(2, message_id, 0, 2))
+                .unwrap()
+                .is_none());
+        }
+        assert_eq!(reassembler.partial.len(), MAX_PARTIAL_MESSAGES);
+
+        // The second message is still held, but the first starts over
+        assert!(reassembler
+            .insert(&fragment_packet(2, 1, 1, 2))
+            .unwrap()
+            .is_some());
+        assert!(reassembler
+            .insert(&fragment_packet(2, 0, 1, 2))
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.expire(), 1);
+        assert_eq!(reassembler.expire(), 0);
+    }
+
+    #[test]
+    fn reassembler_evicts_the_oldest_message_beyond_the_byte_limit() {
+        let clock = VirtualClock::new(0);
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT, Arc::new(clock.clone()));
+        let count = (MAX_REASSEMBLY_BYTES / FRAGMENT_DATA_SIZE) as u16;
+        assert!(reassembler
+            .insert(&fragment_packet(2, 1, 0, count))
+            .unwrap()
+            .is_none());
+        clock.advance(Duration::from_millis(1));
+        assert!(reassembler
+            .insert(&fragment_packet(3, 1, 0, 2))
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.partial.len(), 1);
+        assert_eq!(reassembler.reserved, 2 * FRAGMENT_DATA_SIZE);
+        assert_eq!(reassembler.expire(), 1);
+
+        assert!(matches!(
+            reassembler.insert(&fragment_packet(2, 2, 0, count + 1)),
+            Err(ProtocolError::MessageTooLarge(_))
+        ));
+        assert_eq!(reassembler.partial.len(), 1);
+    }
+
+    #[test]
+    fn reassembler_expires_stalled_messages_and_frees_their_space() {
+        let clock = VirtualClock::new(0);
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT, Arc::new(clock.clone()));
+        assert!(reassembler
+            .insert(&fragment_packet(2, 1, 0, 2))
+            .unwrap()
+            .is_none());
+        clock.advance(Duration::from_millis(REASSEMBLY_TIMEOUT - 1));
+        assert_eq!(reassembler.expire(), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(reassembler.expire(), 1);
+        assert_eq!(reassembler.reserved, 0);
+
+        // The rest of an expired message starts a new one rather than completing it
+        assert!(reassembler
+            .insert(&fragment_packet(2, 1, 1, 2))
+            .unwrap()
+            .is_none());
+    }
+}
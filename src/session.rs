@@ -0,0 +1,130 @@
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use std::time::Duration;
+
+use crate::protocol::Packet;
+
+pub const SESSION_LIFETIME: u64 = 600; // seconds
+pub const HANDSHAKE_TIMEOUT: u64 = 5000; // milliseconds
+const SESSION_SALT: &[u8] = b"atlas-session-v1";
+
+// Our half of an X25519 key agreement, waiting for the other side's ephemeral key
+pub struct Handshake {
+    private_key: EphemeralPrivateKey,
+    pub ephemeral_key: [u8; 32],
+    pub started: u64, // milliseconds
+}
+
+impl Handshake {
+    pub fn new(now: u64) -> Self {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).unwrap();
+        let ephemeral_key = private_key
+            .compute_public_key()
+            .unwrap()
+            .as_ref()
+            .try_into()
+            .unwrap();
+        Self {
+            private_key,
+            ephemeral_key,
+            started: now,
+        }
+    }
+
+    // Derives the session key. Both sides bind the ids and ephemeral keys of the
+    // initiator and the responder, in that order
+    pub fn finish(
+        self,
+        local_id: u16,
+        remote_id: u16,
+        remote_key: &[u8; 32],
+        initiator: bool,
+    ) -> Option<LessSafeKey> {
+        let mut info = Vec::with_capacity(68);
+        let (initiator_id, responder_id, initiator_key, responder_key) = if initiator {
+            (local_id, remote_id, &self.ephemeral_key, remote_key)
+        } else {
+            (remote_id, local_id, remote_key, &self.ephemeral_key)
+        };
+        info.extend_from_slice(&initiator_id.to_le_bytes());
+        info.extend_from_slice(&responder_id.to_le_bytes());
+        info.extend_from_slice(initiator_key);
+        info.extend_from_slice(responder_key);
+
+        agreement::agree_ephemeral(
+            self.private_key,
+            &UnparsedPublicKey::new(&X25519, remote_key),
+            |shared_secret| {
+                let prk = Salt::new(HKDF_SHA256, SESSION_SALT).extract(shared_secret);
+                let info = [info.as_slice()];
+                let okm = prk.expand(&info, &CHACHA20_POLY1305).ok()?;
+                Some(LessSafeKey::new(UnboundKey::from(okm)))
+            },
+        )
+        .ok()
+        .flatten()
+    }
+}
+
+// ChaCha20-Poly1305 key shared with one peer. The key it replaced is kept so packets
+// sealed just before a rotation still open
+pub struct Session {
+    current: LessSafeKey,
+    previous: Option<LessSafeKey>,
+    established: u64, // milliseconds
+}
+
+impl Session {
+    pub fn new(key: LessSafeKey, now: u64) -> Self {
+        Self {
+            current: key,
+            previous: None,
+            established: now,
+        }
+    }
+
+    pub fn rotate(&mut self, key: LessSafeKey, now: u64) {
+        self.previous = Some(std::mem::replace(&mut self.current, key));
+        self.established = now;
+    }
+
+    pub fn age(&self, now: u64) -> Duration {
+        Duration::from_millis(now.saturating_sub(self.established))
+    }
+
+    // Encrypts the payload in place; the header is authenticated as associated data
+    pub fn seal(&self, packet: &mut Packet) {
+        packet.encrypted = true;
+        let aad = packet.header_bytes();
+        self.current
+            .seal_in_place_append_tag(Self::nonce(packet), Aad::from(aad), &mut packet.payload)
+            .unwrap();
+    }
+
+    pub fn open(&self, packet: &mut Packet) -> bool {
+        let aad = packet.header_bytes();
+        for key in std::iter::once(&self.current).chain(&self.previous) {
+            let mut payload = packet.payload.clone();
+            if let Ok(plaintext) =
+                key.open_in_place(Self::nonce(packet), Aad::from(&aad), &mut payload)
+            {
+                let len = plaintext.len();
+                payload.truncate(len);
+                packet.payload = payload;
+                packet.encrypted = false;
+                return true;
+            }
+        }
+        false
+    }
+
+    // Unique per key: each sender's sequence numbers never repeat
+    fn nonce(packet: &Packet) -> Nonce {
+        let mut nonce = [0; aead::NONCE_LEN];
+        nonce[..2].copy_from_slice(&packet.src.to_le_bytes());
+        nonce[2..10].copy_from_slice(&packet.sequence.to_le_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+}
@@ -0,0 +1,202 @@
+use std::cell::{Ref, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::client::Client;
+use crate::clock::{Clock, VirtualClock};
+use crate::config::NodeConfig;
+use crate::node::Node;
+use crate::transport::{SimulatedNetwork, SimulatedTransport, Transport};
+
+// Virtual time the simulation starts at, in milliseconds since the Unix epoch
+pub const SIMULATION_EPOCH: u64 = 1_700_000_000_000;
+// Virtual time that passes on each step
+pub const SIMULATION_TICK: u64 = 1; // milliseconds
+
+struct World {
+    clock: VirtualClock,
+    tick: Duration,
+    nodes: Vec<Node<SimulatedTransport>>,
+    // Nodes added since the last step, started together so their probes find each other
+    joining: Vec<Node<SimulatedTransport>>,
+}
+
+impl World {
+    fn step(&mut self) {
+        for mut node in self.joining.drain(..) {
+            node.start();
+            self.nodes.push(node);
+        }
+        self.clock.advance(self.tick);
+        for node in &mut self.nodes {
+            node.tick();
+            while node.poll(Duration::ZERO) {}
+        }
+    }
+}
+
+// Runs nodes and clients in one thread on a virtual clock over a simulated network.
+// Each step advances the clock by one tick, then lets every node run its timers and
+// handle whatever has arrived, in order of id. Timing can be asserted to the
+// millisecond, and a run takes only as long as the work it does
+pub struct Simulation {
+    clock: VirtualClock,
+    network: SimulatedNetwork,
+    seed: u64,
+    world: Rc<RefCell<World>>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        let clock = VirtualClock::new(SIMULATION_EPOCH);
+        Self {
+            network: SimulatedNetwork::with_clock(seed, clock.clone()),
+            seed,
+            world: Rc::new(RefCell::new(World {
+                clock: clock.clone(),
+                tick: Duration::from_millis(SIMULATION_TICK),
+                nodes: Vec::new(),
+                joining: Vec::new(),
+            })),
+            clock,
+        }
+    }
+
+    pub fn tick(self, tick: Duration) -> Self {
+        self.world.borrow_mut().tick = tick;
+        self
+    }
+
+    pub fn network(&self) -> &SimulatedNetwork {
+        &self.network
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    // Milliseconds since the Unix epoch on the virtual clock
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    // Milliseconds since the simulation started
+    pub fn elapsed(&self) -> u64 {
+        self.clock.now() - SIMULATION_EPOCH
+    }
+
+    // Adds a node listening on its configured atlas address. It starts on the next step
+    pub fn add_node(&mut self, config: NodeConfig) {
+        let transport = self
+            .network
+            .bind(&config.atlas_address())
+            .expect("Failed to bind simulated address");
+        // Web servers are not driven by the simulation and would bind real sockets
+        let config = config.web(false);
+        let seed = self.seed ^ config.id as u64;
+        let node =
+            Node::with_clock(config, transport, Arc::new(self.clock.clone())).election_seed(seed);
+        self.world.borrow_mut().joining.push(node);
+    }
+
+    // Client bound to `addr` that steps the simulation while it waits for replies
+    pub fn client(
+        &self,
+        id: u16,
+        timeout: Duration,
+        addr: &str,
+        remote_addr: &str,
+    ) -> Client<SimulatedClientTransport> {
+        let transport = SimulatedClientTransport {
+            inner: self
+                .network
+                .bind(addr)
+                .expect("Failed to bind simulated address"),
+            world: self.world.clone(),
+        };
+        Client::with_clock(
+            id,
+            timeout,
+            remote_addr,
+            transport,
+            Arc::new(self.clock.clone()),
+        )
+    }
+
+    pub fn node(&self, id: u16) -> Ref<'_, Node<SimulatedTransport>> {
+        Ref::map(self.world.borrow(), |world| {
+            world
+                .nodes
+                .iter()
+                .chain(&world.joining)
+                .find(|node| node.id() == id)
+                .expect("No such node")
+        })
+    }
+
+    pub fn step(&mut self) {
+        self.world.borrow_mut().step();
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.clock.now() + duration.as_millis() as u64;
+        while self.clock.now() < until {
+            self.step();
+        }
+    }
+
+    // Steps until `condition` holds, for at most `max` of virtual time. Returns whether
+    // it held
+    pub fn run_until(&mut self, max: Duration, mut condition: impl FnMut(&Self) -> bool) -> bool {
+        let until = self.clock.now() + max.as_millis() as u64;
+        while !condition(self) {
+            if self.clock.now() >= until {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+}
+
+// Transport for clients inside a simulation. Waiting for a datagram or sleeping steps
+// the simulation instead of blocking, so nodes answer in virtual time
+pub struct SimulatedClientTransport {
+    inner: SimulatedTransport,
+    world: Rc<RefCell<World>>,
+}
+
+impl Transport for SimulatedClientTransport {
+    fn send_to(&self, bytes: &[u8], addr: &str) -> io::Result<()> {
+        self.inner.send_to(bytes, addr)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, String)> {
+        let deadline = self.world.borrow().clock.now() + timeout.as_millis() as u64;
+        loop {
+            match self.inner.recv_from(buffer, Duration::ZERO) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let mut world = self.world.borrow_mut();
+            if world.clock.now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+            }
+            world.step();
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        self.inner.local_addr()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut world = self.world.borrow_mut();
+        let until = world.clock.now() + duration.as_millis() as u64;
+        while world.clock.now() < until {
+            world.step();
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block::Block;
+use crate::utils::sha256;
+
+pub const SEGMENT_SIZE: u64 = 4 * 1024 * 1024; // bytes
+
+// Block length (u32) followed by the SHA256 checksum of the block bytes
+pub const RECORD_HEADER_SIZE: usize = 36;
+
+pub trait BlockStore {
+    fn append(&mut self, block: &Block) -> io::Result<()>;
+    // Drops every block at or above `height`
+    fn truncate(&mut self, height: usize) -> io::Result<()>;
+    fn get(&self, height: usize) -> io::Result<Option<Block>>;
+    fn height_of(&self, hash: &[u8; 32]) -> Option<usize>;
+    fn height(&self) -> usize;
+
+    fn blocks(&self) -> io::Result<Vec<Block>> {
+        (0..self.height())
+            .map(|height| {
+                self.get(height)?.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("missing block #{}", height),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct InMemoryBlockStore {
+    blocks: Vec<Block>,
+    hashes: HashMap<[u8; 32], usize>,
+}
+
+impl Default for InMemoryBlockStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryBlockStore {
+    pub fn new() -> Self {
+        Self {
+            blocks: Vec::new(),
+            hashes: HashMap::new(),
+        }
+    }
+}
+
+impl BlockStore for InMemoryBlockStore {
+    fn append(&mut self, block: &Block) -> io::Result<()> {
+        self.hashes.insert(block.merkle_root, self.blocks.len());
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> io::Result<()> {
+        for block in self.blocks.drain(height.min(self.blocks.len())..) {
+            self.hashes.remove(&block.merkle_root);
+        }
+        Ok(())
+    }
+
+    fn get(&self, height: usize) -> io::Result<Option<Block>> {
+        Ok(self.blocks.get(height).cloned())
+    }
+
+    fn height_of(&self, hash: &[u8; 32]) -> Option<usize> {
+        self.hashes.get(hash).copied()
+    }
+
+    fn height(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    len: u32,
+}
+
+// Append-only segment files of checksummed block records. A record that is cut short
+// or fails its checksum marks a torn write: it and everything after it is discarded
+// when the store is opened.
+pub struct FileBlockStore {
+    dir: PathBuf,
+    index: Vec<Location>,
+    hashes: HashMap<[u8; 32], usize>,
+    segment: u32,
+    segment_len: u64,
+    writer: File,
+    discarded_bytes: u64,
+}
+
+impl FileBlockStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments: Vec<u32> = fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".seg")?.parse().ok()
+            })
+            .collect();
+        segments.sort();
+
+        let mut index = Vec::new();
+        let mut hashes = HashMap::new();
+        let mut prev_block_hash = [0; 32];
+        let mut discarded_bytes = 0;
+        let mut last_segment = (0, 0);
+
+        for (i, &segment) in segments.iter().enumerate() {
+            let path = Self::segment_path(&dir, segment);
+            let bytes = fs::read(&path)?;
+            let mut offset = 0;
+
+            while let Some(block) = Self::read_record(&bytes[offset..]) {
+                if block.prev_block_hash != prev_block_hash {
+                    break;
+                }
+                let len = block.as_bytes().len();
+                hashes.insert(block.merkle_root, index.len());
+                index.push(Location {
+                    segment,
+                    offset: offset as u64,
+                    len: len as u32,
+                });
+                prev_block_hash = block.merkle_root;
+                offset += RECORD_HEADER_SIZE + len;
+            }
+            last_segment = (segment, offset as u64);
+
+            if offset < bytes.len() {
+                // Torn or corrupt record: cut the segment and drop any later ones
+                discarded_bytes += (bytes.len() - offset) as u64;
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(offset as u64)?;
+                for &later in &segments[i + 1..] {
+                    let later_path = Self::segment_path(&dir, later);
+                    discarded_bytes += fs::metadata(&later_path)?.len();
+                    fs::remove_file(later_path)?;
+                }
+                break;
+            }
+        }
+
+        let (segment, segment_len) = last_segment;
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::segment_path(&dir, segment))?;
+
+        Ok(Self {
+            dir,
+            index,
+            hashes,
+            segment,
+            segment_len,
+            writer,
+            discarded_bytes,
+        })
+    }
+
+    // Bytes dropped during recovery when the store was opened
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+        dir.join(format!("{:08}.seg", segment))
+    }
+
+    fn read_record(bytes: &[u8]) -> Option<Block> {
+        let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+        let checksum = bytes.get(4..RECORD_HEADER_SIZE)?;
+        let block_bytes = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+        if sha256(block_bytes) != checksum {
+            return None;
+        }
+        Block::from_bytes(block_bytes).ok()
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn append(&mut self, block: &Block) -> io::Result<()> {
+        let block_bytes = block.as_bytes();
+        if self.segment_len > 0
+            && self.segment_len + (RECORD_HEADER_SIZE + block_bytes.len()) as u64 > SEGMENT_SIZE
+        {
+            self.segment += 1;
+            self.segment_len = 0;
+            self.writer = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(Self::segment_path(&self.dir, self.segment))?;
+        }
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + block_bytes.len());
+        record.extend_from_slice(&(block_bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(&sha256(&block_bytes));
+        record.extend_from_slice(&block_bytes);
+        if let Err(e) = self
+            .writer
+            .write_all(&record)
+            .and_then(|_| self.writer.sync_data())
+        {
+            // Cut off a partially written record so the next append starts clean
+            self.writer.set_len(self.segment_len)?;
+            return Err(e);
+        }
+
+        self.hashes.insert(block.merkle_root, self.index.len());
+        self.index.push(Location {
+            segment: self.segment,
+            offset: self.segment_len,
+            len: block_bytes.len() as u32,
+        });
+        self.segment_len += record.len() as u64;
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> io::Result<()> {
+        let Some(&location) = self.index.get(height) else {
+            return Ok(());
+        };
+        for segment in location.segment + 1..=self.segment {
+            fs::remove_file(Self::segment_path(&self.dir, segment))?;
+        }
+        self.index.truncate(height);
+        self.hashes.retain(|_, h| *h < height);
+
+        self.segment = location.segment;
+        self.segment_len = location.offset;
+        self.writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::segment_path(&self.dir, self.segment))?;
+        self.writer.set_len(location.offset)?;
+        self.writer.sync_data()
+    }
+
+    fn get(&self, height: usize) -> io::Result<Option<Block>> {
+        let Some(location) = self.index.get(height) else {
+            return Ok(None);
+        };
+        let mut file = File::open(Self::segment_path(&self.dir, location.segment))?;
+        file.seek(SeekFrom::Start(location.offset + RECORD_HEADER_SIZE as u64))?;
+        let mut bytes = vec![0; location.len as usize];
+        file.read_exact(&mut bytes)?;
+        Block::from_bytes(&bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn height_of(&self, hash: &[u8; 32]) -> Option<usize> {
+        self.hashes.get(hash).copied()
+    }
+
+    fn height(&self) -> usize {
+        self.index.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use std::time::Duration;
+
+    // Empty store directory unique to the test
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atlas-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Linked blocks a second apart, so each has its own merkle root
+    fn chain(len: usize) -> Vec<Block> {
+        let clock = VirtualClock::new(0);
+        let mut prev_block_hash = [0; 32];
+        (0..len)
+            .map(|_| {
+                clock.advance(Duration::from_secs(1));
+                let block = Block::new(&clock, Vec::new(), prev_block_hash, 1);
+                prev_block_hash = block.merkle_root;
+                block
+            })
+            .collect()
+    }
+
+    fn record(block: &Block) -> Vec<u8> {
+        let block_bytes = block.as_bytes();
+        let mut record = (block_bytes.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&sha256(&block_bytes));
+        record.extend_from_slice(&block_bytes);
+        record
+    }
+
+    fn roots(store: &dyn BlockStore) -> Vec<[u8; 32]> {
+        let blocks = store.blocks().unwrap();
+        blocks.iter().map(|block| block.merkle_root).collect()
+    }
+
+    fn open_with(dir: &Path, blocks: &[Block]) -> FileBlockStore {
+        let mut store = FileBlockStore::open(dir).unwrap();
+        for block in blocks {
+            store.append(block).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn blocks_survive_reopening() {
+        let dir = store_dir("reopen");
+        let blocks = chain(3);
+        drop(open_with(&dir, &blocks));
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.discarded_bytes(), 0);
+        assert_eq!(
+            roots(&store),
+            blocks.iter().map(|b| b.merkle_root).collect::<Vec<_>>()
+        );
+        assert_eq!(store.height_of(&blocks[2].merkle_root), Some(2));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_write_is_cut_off_on_open() {
+        let dir = store_dir("torn");
+        let blocks = chain(4);
+        drop(open_with(&dir, &blocks[..3]));
+        let path = FileBlockStore::segment_path(&dir, 0);
+        let intact_len = fs::metadata(&path).unwrap().len();
+        let torn = &record(&blocks[3])[..50];
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(torn)
+            .unwrap();
+
+        let mut store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 3);
+        assert_eq!(store.discarded_bytes(), torn.len() as u64);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact_len);
+
+        // The next append starts where the torn record was
+        store.append(&blocks[3]).unwrap();
+        drop(store);
+        assert_eq!(FileBlockStore::open(&dir).unwrap().height(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_record_failing_its_checksum_is_dropped_with_those_after_it() {
+        let dir = store_dir("checksum");
+        let blocks = chain(3);
+        drop(open_with(&dir, &blocks));
+        let path = FileBlockStore::segment_path(&dir, 0);
+        let mut bytes = fs::read(&path).unwrap();
+        let record_len = record(&blocks[0]).len();
+        bytes[record_len + RECORD_HEADER_SIZE + 10] ^= 0xFF;
+        fs::write(&path, &bytes).unwrap();
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 1);
+        assert_eq!(store.discarded_bytes(), (bytes.len() - record_len) as u64);
+        assert_eq!(store.height_of(&blocks[1].merkle_root), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn segments_after_a_corrupt_record_are_removed() {
+        let dir = store_dir("segments");
+        let blocks = chain(3);
+        drop(open_with(&dir, &blocks[..2]));
+        // A valid record in a later segment still follows the corrupt one
+        let later_path = FileBlockStore::segment_path(&dir, 1);
+        fs::write(&later_path, record(&blocks[2])).unwrap();
+        let path = FileBlockStore::segment_path(&dir, 0);
+        let intact_len = record(&blocks[0]).len() as u64;
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(intact_len + 20)
+            .unwrap();
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 1);
+        assert_eq!(
+            store.discarded_bytes(),
+            20 + record(&blocks[2]).len() as u64
+        );
+        assert!(!later_path.exists());
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact_len);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_drops_blocks_from_a_height() {
+        let dir = store_dir("truncate");
+        let blocks = chain(4);
+        let mut store = open_with(&dir, &blocks);
+        store.truncate(2).unwrap();
+        assert_eq!(store.height(), 2);
+        assert!(store.get(2).unwrap().is_none());
+        assert_eq!(store.height_of(&blocks[2].merkle_root), None);
+
+        // A fork continues from the cut and is all that is left after reopening
+        let clock = VirtualClock::new(60_000);
+        let fork = Block::new(&clock, Vec::new(), blocks[1].merkle_root, 2);
+        store.append(&fork).unwrap();
+        drop(store);
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.discarded_bytes(), 0);
+        assert_eq!(
+            roots(&store),
+            vec![
+                blocks[0].merkle_root,
+                blocks[1].merkle_root,
+                fork.merkle_root
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn in_memory_truncate_forgets_dropped_hashes() {
+        let blocks = chain(3);
+        let mut store = InMemoryBlockStore::new();
+        for block in &blocks {
+            store.append(block).unwrap();
+        }
+        store.truncate(1).unwrap();
+        assert_eq!(store.height(), 1);
+        assert_eq!(store.height_of(&blocks[0].merkle_root), Some(0));
+        assert_eq!(store.height_of(&blocks[1].merkle_root), None);
+        // Truncating past the end leaves the store as is
+        store.truncate(5).unwrap();
+        assert_eq!(store.height(), 1);
+    }
+}
@@ -1,9 +1,125 @@
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::cache::CacheOperation;
-use crate::utils::serialize_hash;
+use crate::clock::Clock;
+use crate::protocol::{read_array, read_name, read_u16, read_u64, ProtocolError};
+use crate::utils::{deserialize_signature, serialize_hash, serialize_nonce, serialize_signature};
 use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+
+// Signed portion of a transaction, followed on the wire by a 64-byte signature
+pub const TRANSACTION_BODY_SIZE: usize = 174 + CLIENT_SIGNATURE_SIZE;
+pub const TRANSACTION_SIZE: usize = TRANSACTION_BODY_SIZE + 64;
+// Client public key, node id, request timestamp, nonce and the client's signature
+pub const CLIENT_SIGNATURE_SIZE: usize = 122;
+// How far a request timestamp may be from the node's clock
+pub const MAX_REQUEST_AGE: u64 = 30000; // milliseconds
+
+// A client's signature over the request that caused a transaction. It covers the
+// client id, operation, data name, the digest of the value written (zero for reads),
+// the node the request was sent to, the request timestamp and a random nonce, so an
+// auditor can check which client issued it and a node can refuse it when replayed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ClientSignature {
+    #[serde(serialize_with = "serialize_hash")]
+    pub public_key: [u8; 32],
+    pub node_id: u16,
+    pub timestamp: u64, // milliseconds
+    #[serde(serialize_with = "serialize_nonce")]
+    pub nonce: [u8; 16],
+    #[serde(
+        serialize_with = "serialize_signature",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: [u8; 64],
+}
+
+impl ClientSignature {
+    pub fn sign(
+        key_pair: &Ed25519KeyPair,
+        client_id: u16,
+        node_id: u16,
+        operation: CacheOperation,
+        data_name: &str,
+        request_hash: [u8; 32],
+        timestamp: u64,
+    ) -> Self {
+        let mut client_signature = Self {
+            public_key: key_pair.public_key().as_ref().try_into().unwrap(),
+            node_id,
+            timestamp,
+            nonce: [0; 16],
+            signature: [0; 64],
+        };
+        SystemRandom::new()
+            .fill(&mut client_signature.nonce)
+            .expect("Failed to generate nonce");
+        let signing_bytes =
+            client_signature.signing_bytes(client_id, operation, data_name, &request_hash);
+        client_signature.signature = key_pair.sign(&signing_bytes).as_ref().try_into().unwrap();
+        client_signature
+    }
+
+    pub fn verify(
+        &self,
+        client_id: u16,
+        operation: CacheOperation,
+        data_name: &str,
+        request_hash: &[u8; 32],
+    ) -> bool {
+        let signing_bytes = self.signing_bytes(client_id, operation, data_name, request_hash);
+        UnparsedPublicKey::new(&signature::ED25519, &self.public_key)
+            .verify(&signing_bytes, &self.signature)
+            .is_ok()
+    }
+
+    // Whether the request was signed within `max_age` of `now`, either way
+    pub fn is_fresh(&self, now: u64, max_age: u64) -> bool {
+        now.abs_diff(self.timestamp) <= max_age
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            public_key: read_array(bytes, 0)?,
+            node_id: read_u16(bytes, 32)?,
+            timestamp: read_u64(bytes, 34)?,
+            nonce: read_array(bytes, 42)?,
+            signature: read_array(bytes, 58)?,
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CLIENT_SIGNATURE_SIZE);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.node_id.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    fn signing_bytes(
+        &self,
+        client_id: u16,
+        operation: CacheOperation,
+        data_name: &str,
+        request_hash: &[u8; 32],
+    ) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&client_id.to_le_bytes());
+        bytes.push(operation as u8);
+        let mut name_bytes = [0u8; 64];
+        let len = data_name.len().min(64);
+        name_bytes[..len].copy_from_slice(&data_name.as_bytes()[..len]);
+        bytes.extend_from_slice(&name_bytes);
+        bytes.extend_from_slice(request_hash);
+        bytes.extend_from_slice(&self.node_id.to_le_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
@@ -12,63 +128,141 @@ pub struct Transaction {
     pub data_name: String,
     pub operation: CacheOperation,
     pub timestamp: u64,
+    // Digest of the value after the operation (the value served for a Get)
+    #[serde(serialize_with = "serialize_hash")]
+    pub value_hash: [u8; 32],
+    // Digest of the value held before the operation, zero if there was none
+    #[serde(serialize_with = "serialize_hash")]
+    pub prev_value_hash: [u8; 32],
+    pub client_signature: ClientSignature,
+    // Set when access control refused the request; the operation was not applied
+    pub denied: bool,
     #[serde(serialize_with = "serialize_hash")]
     pub hash: [u8; 32],
+    // Originating node's signature over the transaction body
+    #[serde(
+        serialize_with = "serialize_signature",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: [u8; 64],
 }
 
 impl Transaction {
-    pub fn new(node_id: u16, client_id: u16, data_name: String, operation: CacheOperation) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        clock: &dyn Clock,
+        node_id: u16,
+        client_id: u16,
+        data_name: String,
+        operation: CacheOperation,
+        value_hash: [u8; 32],
+        prev_value_hash: [u8; 32],
+        client_signature: ClientSignature,
+    ) -> Self {
         assert!(data_name.len() <= 64, "Data name too long");
+        let timestamp = clock.now_secs();
+        let mut txn = Self {
+            node_id,
+            client_id,
+            data_name,
+            operation,
+            timestamp,
+            value_hash,
+            prev_value_hash,
+            client_signature,
+            denied: false,
+            hash: [0; 32],
+            signature: [0; 64],
+        };
+        txn.hash = txn.calculate_hash();
+        txn
+    }
+
+    // Records the request as refused rather than applied
+    pub fn denied(mut self) -> Self {
+        self.denied = true;
+        self.hash = self.calculate_hash();
+        self
+    }
+
+    // Dates the transaction `timestamp` instead of the current second
+    pub fn at(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self.hash = self.calculate_hash();
+        self
+    }
+
+    pub fn calculate_hash(&self) -> [u8; 32] {
         let mut hash = [0; 32];
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
         hash.copy_from_slice(
             digest(
                 &SHA256,
                 format!(
-                    "{:?}.{:?}.{:?}.{:?}.{:?}",
-                    node_id, client_id, data_name, operation, timestamp
+                    "{:?}.{:?}.{:?}.{:?}.{:?}.{:?}.{:?}.{:?}.{:?}",
+                    self.node_id,
+                    self.client_id,
+                    self.data_name,
+                    self.operation,
+                    self.timestamp,
+                    self.value_hash,
+                    self.prev_value_hash,
+                    self.client_signature.as_bytes(),
+                    self.denied
                 )
                 .as_bytes(),
             )
             .as_ref(),
         );
-        Self {
-            node_id,
-            client_id,
-            data_name,
-            operation,
-            timestamp,
-            hash,
-        }
+        hash
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        assert!(
-            bytes.len() == 109,
-            "Invalid transaction length, expected 109, got {:?}",
-            bytes.len()
-        );
-        Self {
-            node_id: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
-            client_id: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
-            data_name: String::from_utf8(
-                bytes[4..68]
-                    .to_vec()
-                    .into_iter()
-                    .take_while(|&b| b != 0)
-                    .collect(),
+    pub fn verify_signature(&self, public_key: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&self.signing_bytes(), &self.signature)
+            .is_ok()
+    }
+
+    // Checks the embedded client signature, which must be addressed to the originating
+    // node. Only writes sign the value digest
+    pub fn verify_client_signature(&self) -> bool {
+        let request_hash = match self.operation {
+            CacheOperation::Set => self.value_hash,
+            _ => [0; 32],
+        };
+        self.client_signature.node_id == self.node_id
+            && self.client_signature.verify(
+                self.client_id,
+                self.operation,
+                &self.data_name,
+                &request_hash,
             )
-            .unwrap(),
-            operation: CacheOperation::from(bytes[68]),
-            timestamp: u64::from_le_bytes(bytes[69..77].try_into().unwrap()),
-            hash: bytes[77..109].try_into().unwrap(),
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != TRANSACTION_SIZE {
+            return Err(ProtocolError::LengthMismatch {
+                expected: TRANSACTION_SIZE,
+                actual: bytes.len(),
+            });
         }
+        let [operation] = read_array(bytes, 68)?;
+        let [denied] = read_array(bytes, 263)?;
+        Ok(Self {
+            node_id: read_u16(bytes, 0)?,
+            client_id: read_u16(bytes, 2)?,
+            data_name: read_name(bytes, 4)?,
+            operation: CacheOperation::try_from(operation)?,
+            timestamp: read_u64(bytes, 69)?,
+            value_hash: read_array(bytes, 77)?,
+            prev_value_hash: read_array(bytes, 109)?,
+            client_signature: ClientSignature::from_bytes(&bytes[141..263])?,
+            denied: denied != 0,
+            hash: read_array(bytes, 264)?,
+            signature: read_array(bytes, 296)?,
+        })
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    pub fn signing_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.node_id.to_le_bytes());
         bytes.extend_from_slice(&self.client_id.to_le_bytes());
@@ -77,7 +271,17 @@ impl Transaction {
         bytes.extend_from_slice(&name_bytes);
         bytes.push(self.operation as u8);
         bytes.extend_from_slice(&self.timestamp.to_le_bytes());
+        bytes.extend_from_slice(&self.value_hash);
+        bytes.extend_from_slice(&self.prev_value_hash);
+        bytes.extend_from_slice(&self.client_signature.as_bytes());
+        bytes.push(self.denied as u8);
         bytes.extend_from_slice(&self.hash);
         bytes
     }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.signing_bytes();
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
 }
@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock, VirtualClock};
+use crate::utils::{sha256, SplitMix64};
+
+// Datagram transport used by nodes and clients. Addresses are plain strings so the
+// same peer tables work over UDP and over the simulated network
+pub trait Transport {
+    fn send_to(&self, bytes: &[u8], addr: &str) -> io::Result<()>;
+    // Waits up to `timeout` for a datagram, returning its length and the sender's address
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, String)>;
+    fn local_addr(&self) -> String;
+
+    // Pauses between retries. Simulated transports override this to let virtual time pass
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: &str) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, bytes: &[u8], addr: &str) -> io::Result<()> {
+        self.socket.send_to(bytes, addr).map(|_| ())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, String)> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        let (size, addr) = self.socket.recv_from(buffer)?;
+        Ok((size, addr.to_string()))
+    }
+
+    fn local_addr(&self) -> String {
+        self.socket
+            .local_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
+    }
+}
+
+// Faults applied to every datagram crossing the simulated network. Probabilities
+// are between 0 and 1
+#[derive(Debug, Clone)]
+pub struct NetworkConditions {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    pub loss: f64,
+    pub duplication: f64,
+    // Chance that a datagram is held back by up to `max_delay` extra, letting later
+    // datagrams overtake it
+    pub reordering: f64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+        }
+    }
+}
+
+impl NetworkConditions {
+    pub fn delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+        self
+    }
+
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    pub fn duplication(mut self, probability: f64) -> Self {
+        self.duplication = probability;
+        self
+    }
+
+    pub fn reordering(mut self, probability: f64) -> Self {
+        self.reordering = probability;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub partitioned: u64,
+}
+
+struct Datagram {
+    // Milliseconds on the network clock
+    deliver_at: u64,
+    // Send order, breaking ties between datagrams due at the same instant
+    order: u64,
+    from: String,
+    bytes: Vec<u8>,
+}
+
+struct NetworkState {
+    seed: u64,
+    // One generator per direction of each link, so the faults a link sees do not
+    // depend on how sends to other addresses interleave with it
+    links: HashMap<(String, String), SplitMix64>,
+    conditions: NetworkConditions,
+    inboxes: HashMap<String, Vec<Datagram>>,
+    // Groups of addresses that can only reach members of the same group
+    partitions: Vec<HashSet<String>>,
+    next_order: u64,
+    stats: NetworkStats,
+    clock: Arc<dyn Clock>,
+    // Receives never block on virtual time, since nothing would advance the clock
+    virtual_time: bool,
+}
+
+impl NetworkState {
+    fn reachable(&self, from: &str, to: &str) -> bool {
+        if self.partitions.is_empty() {
+            return true;
+        }
+        let group_of = |addr: &str| {
+            self.partitions
+                .iter()
+                .position(|group| group.contains(addr))
+        };
+        group_of(from) == group_of(to)
+    }
+
+    fn link(&mut self, from: &str, to: &str) -> &mut SplitMix64 {
+        let seed = self.seed;
+        self.links
+            .entry((from.to_string(), to.to_string()))
+            .or_insert_with(|| {
+                let hash = sha256(format!("{}>{}", from, to).as_bytes());
+                SplitMix64(seed ^ u64::from_le_bytes(hash[..8].try_into().unwrap()))
+            })
+    }
+
+    fn enqueue(&mut self, from: &str, to: &str, bytes: &[u8], now: u64) {
+        let conditions = self.conditions.clone();
+        let rng = self.link(from, to);
+        let mut delay = rng.between(conditions.min_delay, conditions.max_delay);
+        let reordered = rng.chance(conditions.reordering);
+        if reordered {
+            delay += rng.between(Duration::ZERO, conditions.max_delay);
+            self.stats.reordered += 1;
+        }
+        let order = self.next_order;
+        self.next_order += 1;
+        if let Some(inbox) = self.inboxes.get_mut(to) {
+            inbox.push(Datagram {
+                deliver_at: now + delay.as_millis() as u64,
+                order,
+                from: from.to_string(),
+                bytes: bytes.to_vec(),
+            });
+        }
+    }
+}
+
+// In-process network connecting `SimulatedTransport`s by address. Faults are drawn
+// from generators seeded by `seed`, so a run with the same seed and the same sends
+// on each link sees the same losses, duplicates and delays
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Arc<(Mutex<NetworkState>, Condvar)>,
+}
+
+impl SimulatedNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self::build(seed, Arc::new(SystemClock), false)
+    }
+
+    // Delivers datagrams by `clock` instead of wall-clock time. Receives return at once
+    // when nothing is due, and datagrams only fall due as the clock is advanced
+    pub fn with_clock(seed: u64, clock: VirtualClock) -> Self {
+        Self::build(seed, Arc::new(clock), true)
+    }
+
+    fn build(seed: u64, clock: Arc<dyn Clock>, virtual_time: bool) -> Self {
+        Self {
+            state: Arc::new((
+                Mutex::new(NetworkState {
+                    seed,
+                    links: HashMap::new(),
+                    conditions: NetworkConditions::default(),
+                    inboxes: HashMap::new(),
+                    partitions: Vec::new(),
+                    next_order: 0,
+                    stats: NetworkStats::default(),
+                    clock,
+                    virtual_time,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    pub fn conditions(self, conditions: NetworkConditions) -> Self {
+        self.set_conditions(conditions);
+        self
+    }
+
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        self.state.0.lock().unwrap().conditions = conditions;
+    }
+
+    // Attaches an endpoint at `addr`. Fails if the address is already taken
+    pub fn bind(&self, addr: &str) -> io::Result<SimulatedTransport> {
+        let mut state = self.state.0.lock().unwrap();
+        if state.inboxes.contains_key(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        }
+        state.inboxes.insert(addr.to_string(), Vec::new());
+        Ok(SimulatedTransport {
+            network: self.clone(),
+            addr: addr.to_string(),
+        })
+    }
+
+    // Splits the network so addresses only reach others in the same group. Addresses
+    // left out of every group form one more group of their own
+    pub fn partition(&self, groups: &[&[&str]]) {
+        let mut state = self.state.0.lock().unwrap();
+        state.partitions = groups
+            .iter()
+            .map(|group| group.iter().map(|addr| addr.to_string()).collect())
+            .collect();
+    }
+
+    pub fn heal(&self) {
+        self.state.0.lock().unwrap().partitions.clear();
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.state.0.lock().unwrap().stats.clone()
+    }
+
+    fn send(&self, from: &str, to: &str, bytes: &[u8]) {
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        state.stats.sent += 1;
+        if !state.reachable(from, to) {
+            state.stats.partitioned += 1;
+            return;
+        }
+        let conditions = state.conditions.clone();
+        if state.link(from, to).chance(conditions.loss) {
+            state.stats.lost += 1;
+            return;
+        }
+        let now = state.clock.now();
+        state.enqueue(from, to, bytes, now);
+        if state.link(from, to).chance(conditions.duplication) {
+            state.stats.duplicated += 1;
+            state.enqueue(from, to, bytes, now);
+        }
+        ready.notify_all();
+    }
+
+    fn recv(
+        &self,
+        addr: &str,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<(usize, String)> {
+        let (lock, ready) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let deadline = state.clock.now() + timeout.as_millis() as u64;
+        loop {
+            let now = state.clock.now();
+            let inbox = state.inboxes.get_mut(addr).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("{} is not bound", addr),
+                )
+            })?;
+            let next = inbox
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, datagram)| (datagram.deliver_at, datagram.order))
+                .map(|(i, datagram)| (i, datagram.deliver_at));
+            if let Some((i, deliver_at)) = next {
+                if deliver_at <= now {
+                    let datagram = inbox.swap_remove(i);
+                    state.stats.delivered += 1;
+                    let size = datagram.bytes.len().min(buffer.len());
+                    buffer[..size].copy_from_slice(&datagram.bytes[..size]);
+                    return Ok((size, datagram.from));
+                }
+            }
+            if state.virtual_time || now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+            }
+            // Wake for the next datagram falling due, a new send or the deadline
+            let wait_until = next.map_or(deadline, |(_, deliver_at)| deliver_at.min(deadline));
+            state = ready
+                .wait_timeout(state, Duration::from_millis(wait_until - now))
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn unbind(&self, addr: &str) {
+        self.state.0.lock().unwrap().inboxes.remove(addr);
+    }
+}
+
+pub struct SimulatedTransport {
+    network: SimulatedNetwork,
+    addr: String,
+}
+
+impl Transport for SimulatedTransport {
+    fn send_to(&self, bytes: &[u8], addr: &str) -> io::Result<()> {
+        self.network.send(&self.addr, addr, bytes);
+        Ok(())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, String)> {
+        self.network.recv(&self.addr, buffer, timeout)
+    }
+
+    fn local_addr(&self) -> String {
+        self.addr.clone()
+    }
+}
+
+impl Drop for SimulatedTransport {
+    fn drop(&mut self) {
+        self.network.unbind(&self.addr);
+    }
+}
@@ -1,3 +1,6 @@
+use ring::digest::{digest, SHA256};
+use std::time::Duration;
+
 pub fn serialize_hash<S>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -9,9 +12,80 @@ where
     serializer.serialize_str(&hex)
 }
 
+pub fn serialize_signature<S>(signature: &[u8; 64], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&hex_string(signature))
+}
+
+pub fn serialize_nonce<S>(nonce: &[u8; 16], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&hex_string(nonce))
+}
+
+pub fn deserialize_signature<'de, D>(deserializer: D) -> Result<[u8; 64], D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let hex = <String as serde::Deserialize>::deserialize(deserializer)?;
+    parse_hex(&hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| serde::de::Error::custom("invalid signature"))
+}
+
 pub fn hex_string(bytes: &[u8]) -> String {
     bytes
         .iter()
         .map(|b| format!("{:02x}", b))
         .collect::<String>()
 }
+
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(digest(&SHA256, bytes).as_ref());
+    hash
+}
+
+// SplitMix64, so a seed replays the same sequence of numbers on every platform
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    pub fn between(&mut self, min: Duration, max: Duration) -> Duration {
+        if max <= min {
+            return min;
+        }
+        let span = (max - min).as_nanos() as u64;
+        min + Duration::from_nanos(self.next_u64() % (span + 1))
+    }
+}
@@ -2,21 +2,27 @@ use base64;
 use sha1::{Digest, Sha1};
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::env;
 use std::fs;
-use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
 type WebSocketConnections = Arc<Mutex<HashMap<usize, std::net::TcpStream>>>;
 
 const FRAME_BUFFER: usize = 10 * 1024 * 1024;
+// How often the accept loop checks for new connections and the stop flag
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+// Longest a connection thread blocks on a read before checking the stop flag. Also
+// how long a new connection has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
 
 thread_local! {
     static READ_BUFFER: RefCell<Vec<u8>> = RefCell::new(vec![0; FRAME_BUFFER]);
@@ -27,61 +33,82 @@ pub enum WebSignal {
     GetPeers { client_id: usize },
     GetCache { client_id: usize },
     GetHistory { client_id: usize, data_name: String },
+    GetProof { client_id: usize, transaction_hash: String },
+    DeleteData { data_name: String },
 }
 
 #[derive(Debug)]
 pub struct WebServer {
     listener: TcpListener,
+    web_root: PathBuf,
     connections: WebSocketConnections,
     signal_tx: Sender<WebSignal>,
+    stopped: AtomicBool,
+    accept_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl WebServer {
-    pub fn new(address: &str) -> (Arc<Self>, Receiver<WebSignal>) {
+    pub fn new(address: &str, web_root: &str) -> io::Result<(Arc<Self>, Receiver<WebSignal>)> {
         let (tx, rx) = channel();
-        let listener = match TcpListener::bind(address) {
-            Ok(l) => l,
-            Err(e) => {
-                eprintln!("Failed to bind TCP listener: {}", e);
-                std::process::exit(1);
-            }
-        };
+        let listener = TcpListener::bind(address)?;
+        // Accepting without blocking lets the accept loop see the stop flag
+        listener.set_nonblocking(true)?;
         let connections = Arc::new(Mutex::new(HashMap::new()));
 
         let server = Self {
             listener,
+            web_root: PathBuf::from(web_root),
             connections,
             signal_tx: tx,
+            stopped: AtomicBool::new(false),
+            accept_thread: Mutex::new(None),
         };
 
-        (Arc::new(server), rx)
+        Ok((Arc::new(server), rx))
     }
 
     pub fn run(self: &Arc<Self>) {
-        println!(
-            "Web server running on {}",
-            self.listener.local_addr().unwrap_or_else(|e| {
-                eprintln!("Failed to get local address: {}", e);
-                std::process::exit(1);
-            })
-        );
+        match self.listener.local_addr() {
+            Ok(address) => println!("Web server running on {}", address),
+            Err(e) => eprintln!("Failed to get local address: {}", e),
+        }
 
         let server_clone = Arc::clone(self);
-        thread::spawn(move || {
-            for stream in server_clone.listener.incoming() {
-                match stream {
-                    Ok(stream) => {
+        let accept_thread = thread::spawn(move || {
+            while !server_clone.stopped.load(Ordering::SeqCst) {
+                match server_clone.listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = stream
+                            .set_nonblocking(false)
+                            .and_then(|_| stream.set_read_timeout(Some(READ_TIMEOUT)))
+                        {
+                            eprintln!("Failed to configure connection: {}", e);
+                            continue;
+                        }
                         let connections = Arc::clone(&server_clone.connections);
                         let server = Arc::clone(&server_clone);
                         thread::spawn(move || server.handle_request(stream, connections));
                     }
-                    Err(e) => {
-                        eprintln!("Failed to accept connection: {}", e);
-                        continue;
-                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                    Err(e) => eprintln!("Failed to accept connection: {}", e),
                 }
             }
         });
+        *self.accept_thread.lock().unwrap() = Some(accept_thread);
+    }
+
+    // Stops accepting connections and closes every open WebSocket. The accept loop and
+    // connection threads notice the stop flag within one poll or read timeout. The
+    // listener is closed once the server is dropped
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let Some(accept_thread) = self.accept_thread.lock().unwrap().take() else {
+            return;
+        };
+        let _ = accept_thread.join();
+        for (_, stream) in self.connections.lock().unwrap().drain() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
     }
 
     // New methods for WebSocket API
@@ -89,7 +116,7 @@ impl WebServer {
         let connections = self.connections.lock().unwrap();
         for stream in connections.values() {
             if let Ok(mut stream) = stream.try_clone() {
-                let _ = self.send_frame(&mut stream, message);
+                self.send_frame(&mut stream, message);
             }
         }
     }
@@ -114,8 +141,12 @@ impl WebServer {
             let mut buffer = buffer.borrow_mut();
             buffer.resize(FRAME_BUFFER, 0);
 
-            stream.read(&mut buffer).unwrap();
-            let request = String::from_utf8_lossy(&buffer[..]);
+            // Nothing arrived before the read timeout, or the peer hung up
+            let n = stream.read(&mut buffer).unwrap_or(0);
+            if n == 0 {
+                return;
+            }
+            let request = String::from_utf8_lossy(&buffer[..n]);
 
             if request.contains("Upgrade: websocket") {
                 let request = request.to_string();
@@ -155,7 +186,7 @@ impl WebServer {
             accept_key
         );
 
-        stream.write(response.as_bytes()).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
 
         // Register the connection
         let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
@@ -193,6 +224,23 @@ impl WebServer {
                                                     });
                                             }
                                         }
+                                        Some("proof") => {
+                                            if let Some(transaction_hash) = query["params"].as_str()
+                                            {
+                                                let _ = self.signal_tx.send(WebSignal::GetProof {
+                                                    client_id: id,
+                                                    transaction_hash: transaction_hash.to_string(),
+                                                });
+                                            }
+                                        }
+                                        Some("delete") => {
+                                            if let Some(data_name) = query["params"].as_str() {
+                                                let _ =
+                                                    self.signal_tx.send(WebSignal::DeleteData {
+                                                        data_name: data_name.to_string(),
+                                                    });
+                                            }
+                                        }
                                         Some("chain") => {
                                             let _ = self
                                                 .signal_tx
@@ -214,6 +262,10 @@ impl WebServer {
                             }
                         }
                     }
+                    // An idle connection is kept until the server stops
+                    Err(e)
+                        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                            && !self.stopped.load(Ordering::SeqCst) => {}
                     _ => {
                         connections.lock().unwrap().remove(&id);
                         break;
@@ -224,7 +276,7 @@ impl WebServer {
     }
 
     fn get_content_type(&self, path: &str) -> &str {
-        match path.split('.').last().unwrap_or("") {
+        match path.split('.').next_back().unwrap_or("") {
             "txt" => "text/plain",
             "html" => "text/html",
             "js" => "application/javascript",
@@ -252,8 +304,7 @@ impl WebServer {
             .nth(1)
             .expect("Invalid request");
 
-        let current_dir = env::current_dir().unwrap();
-        let dist_path = current_dir.join("web/dist");
+        let dist_path = &self.web_root;
         let file_path = if path == "/" {
             dist_path.join("index.html")
         } else {
@@ -272,15 +323,14 @@ impl WebServer {
             .into_iter()
             .chain(content)
             .collect::<Vec<u8>>(),
-            Err(_) => format!(
-                "HTTP/1.1 404 Not Found\r\n\
+            Err(_) => "HTTP/1.1 404 Not Found\r\n\
                     Content-Length: 9\r\n\r\n\
                     Not Found"
-            )
-            .into_bytes(),
+                .as_bytes()
+                .to_vec(),
         };
 
-        stream.write(&response).unwrap();
+        let _ = stream.write_all(&response);
     }
 
     fn generate_accept_key(&self, key: &str) -> String {
@@ -331,6 +381,6 @@ impl WebServer {
         }
 
         frame.extend_from_slice(payload);
-        stream.write(&frame).unwrap();
+        let _ = stream.write_all(&frame);
     }
 }
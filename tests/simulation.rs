@@ -0,0 +1,369 @@
+use atlas::acl::{AclRule, Permission};
+use atlas::client::ClientError;
+use atlas::config::NodeConfig;
+use atlas::keys;
+use atlas::protocol::{
+    AckPayload, ErrorCode, HeartbeatPayload, Packet, PacketType, ProbePayload, PACKET_BUFFER_SIZE,
+};
+use atlas::simulation::Simulation;
+use atlas::transport::{NetworkConditions, Transport};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::time::Duration;
+
+const SEED: u64 = 42;
+
+fn address(id: u16) -> String {
+    format!("127.0.0.1:{}", 7000 + id)
+}
+
+// Three nodes with node 1 as the first leader, sealing a block every second
+fn cluster(seed: u64) -> Simulation {
+    let mut simulation = Simulation::new(seed);
+    for id in 1..=3 {
+        let mut config = NodeConfig::new(id, "127.0.0.1")
+            .atlas_port(7000 + id)
+            .block_period(1);
+        config = if id == 1 {
+            config.leader(1)
+        } else {
+            config.seed_peer(1, &address(1))
+        };
+        simulation.add_node(config);
+    }
+    simulation
+}
+
+fn meshed(simulation: &Simulation) -> bool {
+    (1..=3).all(|id| simulation.node(id).status().peers == 2)
+}
+
+fn leader(simulation: &Simulation, ids: &[u16]) -> Option<u16> {
+    ids.iter()
+        .copied()
+        .find(|&id| simulation.node(id).is_leader())
+}
+
+fn tip(simulation: &Simulation, id: u16) -> Option<[u8; 64]> {
+    simulation
+        .node(id)
+        .chain()
+        .last()
+        .map(|block| block.signature)
+}
+
+fn converged(simulation: &Simulation, ids: &[u16]) -> bool {
+    ids.iter()
+        .all(|&id| tip(simulation, id) == tip(simulation, ids[0]))
+}
+
+fn recorded(simulation: &Simulation, id: u16, transaction_hash: &[u8; 32]) -> bool {
+    simulation.node(id).chain().iter().any(|block| {
+        block
+            .transactions
+            .iter()
+            .any(|txn| &txn.hash == transaction_hash)
+    })
+}
+
+// A packet from node 4, which is not part of the cluster but signs with `key_pair`
+fn from_outsider(
+    simulation: &Simulation,
+    key_pair: &Ed25519KeyPair,
+    packet_type: PacketType,
+    sequence: u64,
+    payload: Vec<u8>,
+) -> Vec<u8> {
+    let mut packet = Packet::new(4, 1, packet_type, payload);
+    packet.sequence = sequence;
+    packet.timestamp = simulation.now();
+    packet.signature = key_pair
+        .sign(&packet.signing_bytes())
+        .as_ref()
+        .try_into()
+        .unwrap();
+    packet.as_bytes()
+}
+
+// Admits node 4 to node 1 with a probe that opens no session, as its ephemeral key
+// is all zero
+fn outsider_probe(simulation: &Simulation, key_pair: &Ed25519KeyPair) -> Vec<u8> {
+    let public_key = key_pair.public_key().as_ref().try_into().unwrap();
+    from_outsider(
+        simulation,
+        key_pair,
+        PacketType::Probe,
+        1,
+        ProbePayload::new(4, public_key, [0; 32]).as_bytes(),
+    )
+}
+
+#[test]
+fn writes_reach_every_chain_despite_loss() {
+    let mut simulation = cluster(SEED);
+    simulation
+        .network()
+        .set_conditions(NetworkConditions::default().loss(0.1));
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    let transaction_hash = client
+        .set_data("/satellite/1", b"online")
+        .expect("Write was not confirmed");
+    assert!(simulation.run_until(Duration::from_secs(10), |simulation| {
+        (1..=3).all(|id| recorded(simulation, id, &transaction_hash))
+    }));
+    assert!(simulation.network().stats().lost > 0);
+    assert_eq!(
+        client.get_data("/satellite/1").unwrap().as_deref(),
+        Some(&b"online"[..])
+    );
+}
+
+#[test]
+fn writes_through_different_nodes_settle_on_one_value() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+
+    let mut clients: Vec<_> = (1..=3)
+        .map(|id| {
+            let addr = format!("127.0.0.1:{}", 9000 + id);
+            simulation.client(9, Duration::from_secs(1), &addr, &address(id))
+        })
+        .collect();
+
+    // Each side's write is replicated to the other only after the other's own write
+    simulation.network().partition(&[
+        &[&address(1), "127.0.0.1:9001"],
+        &[&address(2), &address(3), "127.0.0.1:9002", "127.0.0.1:9003"],
+    ]);
+    clients[0]
+        .set_data("/satellite/4", b"east")
+        .expect("Write was not confirmed");
+    clients[1]
+        .set_data("/satellite/4", b"west")
+        .expect("Write was not confirmed");
+    simulation.network().heal();
+    simulation.run_for(Duration::from_secs(2));
+
+    let values: Vec<_> = clients
+        .iter_mut()
+        .map(|client| client.get_data("/satellite/4").unwrap())
+        .collect();
+    assert!(values[0].is_some());
+    assert!(values.iter().all(|value| *value == values[0]));
+}
+
+#[test]
+fn a_delete_right_after_a_write_wins() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+
+    // Both land in the same second, whatever order their hashes fall in
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    for i in 0..8 {
+        let name = format!("/debris/{}", i);
+        client
+            .set_data(&name, b"tracked")
+            .expect("Write was not confirmed");
+        client.delete_data(&name).expect("Delete was not confirmed");
+    }
+    simulation.run_for(Duration::from_secs(2));
+    for i in 0..8 {
+        assert_eq!(client.get_data(&format!("/debris/{}", i)).unwrap(), None);
+    }
+}
+
+#[test]
+fn data_is_not_sent_to_a_peer_without_a_session() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    client
+        .set_data("/satellite/5", b"online")
+        .expect("Write was not confirmed");
+
+    let key_pair = keys::ephemeral();
+    let peer = simulation.network().bind(&address(4)).unwrap();
+    peer.send_to(&outsider_probe(&simulation, &key_pair), &address(1))
+        .unwrap();
+    simulation.run_for(Duration::from_secs(2));
+
+    let mut buffer = [0; PACKET_BUFFER_SIZE];
+    let mut received = Vec::new();
+    while let Ok((size, _)) = peer.recv_from(&mut buffer, Duration::ZERO) {
+        received.push(Packet::from_bytes(&buffer[..size]).unwrap());
+    }
+    assert!(received
+        .iter()
+        .any(|packet| packet.packet_type == PacketType::Sync));
+    assert!(received
+        .iter()
+        .all(|packet| packet.encrypted || !packet.carried_type().unwrap().carries_data()));
+}
+
+#[test]
+fn transactions_synced_in_chain_pages_are_no_longer_pending() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+
+    // Node 3 receives the transaction once it rejoins, but the block only with the
+    // chain it catches up on
+    simulation.network().partition(&[
+        &[&address(1), &address(2), "127.0.0.1:9000"],
+        &[&address(3)],
+    ]);
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    let transaction_hash = client
+        .set_data("/satellite/6", b"online")
+        .expect("Write was not confirmed");
+    assert!(simulation.run_until(Duration::from_secs(5), |simulation| {
+        recorded(simulation, 1, &transaction_hash)
+    }));
+    simulation.network().heal();
+
+    assert!(simulation.run_until(Duration::from_secs(10), |simulation| {
+        recorded(simulation, 3, &transaction_hash)
+    }));
+    simulation.run_for(Duration::from_secs(2));
+    assert!((1..=3).all(|id| simulation.node(id).status().pending_transactions == 0));
+}
+
+#[test]
+fn denied_reads_are_told_apart_from_missing_names() {
+    let mut simulation = Simulation::new(SEED);
+    simulation.add_node(
+        NodeConfig::new(1, "127.0.0.1")
+            .atlas_port(7001)
+            .leader(1)
+            .acl_rule(AclRule::new(
+                None,
+                "/public/*",
+                &[Permission::Read, Permission::Write],
+            )),
+    );
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    client
+        .set_data("/public/1", b"online")
+        .expect("Write was not confirmed");
+
+    assert_eq!(
+        client.get_data("/public/1").unwrap().as_deref(),
+        Some(&b"online"[..])
+    );
+    assert_eq!(client.get_data("/public/2").unwrap(), None);
+    assert!(matches!(
+        client.get_data("/private/1"),
+        Err(ClientError::Refused(ErrorCode::AccessDenied))
+    ));
+}
+
+#[test]
+fn a_peer_cannot_name_another_node_leader() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+    let term = simulation.node(1).status().term;
+
+    let key_pair = keys::ephemeral();
+    let peer = simulation.network().bind(&address(4)).unwrap();
+    peer.send_to(&outsider_probe(&simulation, &key_pair), &address(1))
+        .unwrap();
+    simulation.run_for(Duration::from_millis(10));
+    let heartbeat = from_outsider(
+        &simulation,
+        &key_pair,
+        PacketType::Heartbeat,
+        2,
+        HeartbeatPayload::new(4, term + 5, 2, 0, 0, [0; 32]).as_bytes(),
+    );
+    peer.send_to(&heartbeat, &address(1)).unwrap();
+    simulation.run_for(Duration::from_millis(10));
+
+    assert_eq!(simulation.node(1).status().leader, Some(1));
+    assert_eq!(simulation.node(1).status().term, term);
+}
+
+#[test]
+fn forged_sequence_numbers_do_not_lock_a_client_out() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+
+    // An unsigned ack claiming to come from client 9, far ahead in its sequence
+    let mut forged = Packet::new(9, 1, PacketType::Ack, AckPayload::new(0).as_bytes());
+    forged.sequence = u64::MAX - 5;
+    forged.timestamp = simulation.now();
+    let attacker = simulation.network().bind("127.0.0.1:9666").unwrap();
+    attacker.send_to(&forged.as_bytes(), &address(1)).unwrap();
+    simulation.run_for(Duration::from_millis(10));
+
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(1));
+    assert!(client.set_data("/satellite/3", b"online").is_ok());
+}
+
+#[test]
+fn partitioned_leader_is_replaced_and_rejoins() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+    simulation.run_for(Duration::from_secs(2));
+
+    simulation
+        .network()
+        .partition(&[&[&address(1)], &[&address(2), &address(3)]]);
+    assert!(simulation.run_until(Duration::from_secs(15), |simulation| {
+        leader(simulation, &[2, 3]).is_some()
+    }));
+    let new_leader = leader(&simulation, &[2, 3]).unwrap();
+    simulation.run_for(Duration::from_secs(3));
+    let majority_height = simulation.node(new_leader).chain().len();
+
+    // The old leader kept sealing blocks nobody else accepted, which must give way
+    simulation.network().heal();
+    assert!(simulation.run_until(Duration::from_secs(20), |simulation| {
+        converged(simulation, &[1, 2, 3]) && simulation.node(1).status().leader == Some(new_leader)
+    }));
+    assert!(!simulation.node(1).is_leader());
+    assert!(simulation.node(1).chain().len() >= majority_height);
+}
+
+#[test]
+fn leader_failover_keeps_committed_blocks() {
+    let mut simulation = cluster(SEED);
+    assert!(simulation.run_until(Duration::from_secs(10), meshed));
+    let mut client = simulation.client(9, Duration::from_secs(1), "127.0.0.1:9000", &address(2));
+    let transaction_hash = client
+        .set_data("/satellite/2", b"tracking")
+        .expect("Write was not confirmed");
+    assert!(simulation.run_until(Duration::from_secs(10), |simulation| {
+        (1..=3).all(|id| recorded(simulation, id, &transaction_hash))
+    }));
+
+    let term = simulation.node(2).status().term;
+
+    // Cut the leader off for good, leaving the client with the majority
+    simulation.network().partition(&[
+        &[&address(1)],
+        &[&address(2), &address(3), "127.0.0.1:9000"],
+    ]);
+    assert!(simulation.run_until(Duration::from_secs(15), |simulation| {
+        leader(simulation, &[2, 3]).is_some()
+    }));
+    assert!(simulation.node(2).status().term > term);
+
+    let write_hash = transaction_hash;
+    let transaction_hash = client
+        .delete_data("/satellite/2")
+        .expect("Delete was not confirmed");
+    assert!(simulation.run_until(Duration::from_secs(10), |simulation| {
+        [2, 3]
+            .iter()
+            .all(|&id| recorded(simulation, id, &transaction_hash))
+    }));
+    assert!([2, 3]
+        .iter()
+        .all(|&id| recorded(&simulation, id, &write_hash)));
+    assert!(converged(&simulation, &[2, 3]));
+
+    // Answered as not found rather than left to time out
+    let asked_at = simulation.elapsed();
+    assert_eq!(client.get_data("/satellite/2").unwrap(), None);
+    assert!(simulation.elapsed() - asked_at < 1000);
+}
@@ -0,0 +1,52 @@
+use atlas::block::BLOCK_PERIOD;
+use atlas::config::NodeConfig;
+use atlas::protocol::{ACK_TIMEOUT, MAX_RETRIES};
+use atlas::simulation::{Simulation, SIMULATION_TICK};
+use std::time::Duration;
+
+const SEED: u64 = 42;
+
+#[test]
+fn leader_seals_a_block_every_block_period() {
+    let mut simulation = Simulation::new(SEED);
+    simulation.add_node(NodeConfig::new(1, "127.0.0.1").atlas_port(7001).leader(1));
+
+    // The first block is sealed on the first step
+    simulation.step();
+    assert_eq!(simulation.node(1).chain().len(), 1);
+    assert_eq!(simulation.elapsed(), SIMULATION_TICK);
+
+    let period = Duration::from_secs(BLOCK_PERIOD);
+    for height in 2..=3 {
+        assert!(simulation.run_until(period, |simulation| {
+            simulation.node(1).chain().len() == height
+        }));
+        assert_eq!(
+            simulation.elapsed(),
+            (height as u64 - 1) * BLOCK_PERIOD * 1000
+        );
+    }
+}
+
+#[test]
+fn unacknowledged_packets_are_resent_every_ack_timeout() {
+    let mut simulation = Simulation::new(SEED);
+    // Nothing listens on the seed's address, so the probe is never acknowledged
+    simulation.add_node(
+        NodeConfig::new(2, "127.0.0.1")
+            .atlas_port(7002)
+            .seed_peer(1, "127.0.0.1:7001"),
+    );
+
+    let timeout = Duration::from_millis(ACK_TIMEOUT);
+    for attempt in 1..=MAX_RETRIES as u64 {
+        assert!(simulation.run_until(timeout, |simulation| {
+            simulation.network().stats().sent == attempt + 1
+        }));
+        assert_eq!(simulation.elapsed(), attempt * ACK_TIMEOUT);
+    }
+
+    // Given up after the last retry
+    simulation.run_for(timeout * 4);
+    assert_eq!(simulation.network().stats().sent, 1 + MAX_RETRIES as u64);
+}
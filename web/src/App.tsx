@@ -21,7 +21,7 @@ import {
 } from "@mui/material"
 import { createTheme, ThemeProvider } from "@mui/material/styles"
 import { CssBaseline } from "@mui/material"
-import { KeyboardArrowDown, KeyboardArrowUp, KeyboardDoubleArrowDown, Storage } from "@mui/icons-material"
+import { DeleteOutline, KeyboardArrowDown, KeyboardArrowUp, KeyboardDoubleArrowDown, Storage } from "@mui/icons-material"
 
 const darkTheme = createTheme({
   palette: {
@@ -43,6 +43,17 @@ type Transaction = {
   data_name: string
   operation: string
   timestamp: number
+  value_hash: string
+  prev_value_hash: string
+  client_signature: {
+    public_key: string
+    node_id: number
+    timestamp: number
+    nonce: string
+    signature: string
+  }
+  denied: boolean
+  hash: string
 }
 
 type Block = {
@@ -53,6 +64,23 @@ type Block = {
   transaction_count: number
 }
 
+type RejectedPeer = {
+  id: number
+  address: string | null
+  public_key: string
+  reason: string
+  rejected: number
+}
+
+type Peer = {
+  id: number
+  address: string | null
+  public_key: string | null
+  state: "self" | "alive" | "suspect" | "dead"
+  last_seen: number | null
+  rtt: number | null
+}
+
 type Cache = {
   name: string
   size: number
@@ -61,11 +89,19 @@ type Cache = {
   transactions: number
 }
 
+type Tombstone = {
+  name: string
+  deleted_at: number
+}
+
 function App() {
   const [chain, setChain] = useState<Block[]>([])
   const [cache, setCache] = useState<Cache[]>([])
+  const [tombstones, setTombstones] = useState<Tombstone[]>([])
   const [showHistory, setShowHistory] = useState<string>("")
   const [history, setHistory] = useState<Transaction[]>([])
+  const [rejectedPeers, setRejectedPeers] = useState<RejectedPeer[]>([])
+  const [peers, setPeers] = useState<Peer[]>([])
   const wsRef = useRef<WebSocket | null>(null)
   const chainDom = useRef<HTMLDivElement | null>(null)
   const getChain = () => {
@@ -85,6 +121,15 @@ function App() {
     )
   }
 
+  const deleteData = (dataName: string) => {
+    wsRef.current?.send(
+      JSON.stringify({
+        data: "delete",
+        params: dataName,
+      } as Query)
+    )
+  }
+
   const getCache = () => {
     wsRef.current?.send(
       JSON.stringify({
@@ -93,6 +138,14 @@ function App() {
     )
   }
 
+  const getPeers = () => {
+    wsRef.current?.send(
+      JSON.stringify({
+        data: "peers",
+      } as Query)
+    )
+  }
+
   useEffect(() => {
     const ws = new WebSocket("ws://localhost:7010")
     wsRef.current = ws
@@ -100,6 +153,7 @@ function App() {
       console.log("Connected to server")
       getChain()
       getCache()
+      getPeers()
     }
     ws.onmessage = (event) => {
       const data = JSON.parse(event.data)
@@ -115,6 +169,13 @@ function App() {
       }
       if (data.type === "cache") {
         setCache(data.value)
+        setTombstones(data.tombstones ?? [])
+      }
+      if (data.type === "peers") {
+        setPeers(data.value)
+      }
+      if (data.type === "rejected_peer") {
+        setRejectedPeers((prevRejected) => [...prevRejected, data.value])
       }
     }
   }, [])
@@ -127,6 +188,8 @@ function App() {
     }
   }, [chain])
 
+  const liveNodes = peers.filter((peer) => peer.state === "self" || peer.state === "alive").length
+
   const [page, setPage] = useState(0)
   const rowsPerPage = 10
   const handleChangePage = (_event: unknown, newPage: number) => {
@@ -153,8 +216,15 @@ function App() {
                   ATLAS: Advanced Twin Linkage And Synchronization
                 </Typography>
 
-                <Typography variant="body1" component="div">
-                  Nodes: 2 - Blocks: {chain.length} - Cached data: {cache.length}
+                <Typography
+                  variant="body1"
+                  component="div"
+                  title={rejectedPeers
+                    .map((peer) => `node ${peer.id} at ${peer.address ?? "unknown"}: ${peer.reason}`)
+                    .join("\n")}
+                >
+                  Nodes: {liveNodes}/{peers.length} - Blocks: {chain.length} - Cached data: {cache.length}
+                  {rejectedPeers.length > 0 && ` - Rejected peers: ${rejectedPeers.length}`}
                 </Typography>
               </Toolbar>
             </AppBar>
@@ -242,11 +312,30 @@ function App() {
                       data={data}
                       history={history}
                       getHistory={getHistory}
+                      deleteData={deleteData}
                       showHistory={showHistory}
                       setShowHistory={setShowHistory}
                       key={i}
                     />
                   ))}
+                  {tombstones.map((tombstone) => (
+                    <CacheRow
+                      data={{
+                        name: tombstone.name,
+                        size: 0,
+                        last_updated: tombstone.deleted_at,
+                        last_accessed: 0,
+                        transactions: 0,
+                      }}
+                      deleted
+                      history={history}
+                      getHistory={getHistory}
+                      deleteData={deleteData}
+                      showHistory={showHistory}
+                      setShowHistory={setShowHistory}
+                      key={`deleted-${tombstone.name}`}
+                    />
+                  ))}
                 </TableBody>
                 <TableFooter>
                   <TableRow>
@@ -264,6 +353,51 @@ function App() {
             </TableContainer>
           </Box>
         </Grid>
+        <Grid size={12}>
+          <TableContainer component={Paper}>
+            <Table size="small" sx={{ backgroundColor: "#1A2027" }}>
+              <TableHead>
+                <TableRow
+                  sx={{
+                    "& .MuiTableCell-root": {
+                      color: (theme) => theme.palette.text.secondary,
+                      textAlign: "center",
+                    },
+                  }}
+                >
+                  <TableCell>Node</TableCell>
+                  <TableCell>Address</TableCell>
+                  <TableCell>State</TableCell>
+                  <TableCell>Public Key</TableCell>
+                  <TableCell>Latency</TableCell>
+                  <TableCell>Last Seen</TableCell>
+                </TableRow>
+              </TableHead>
+              <TableBody>
+                {peers.map((peer) => (
+                  <TableRow
+                    key={peer.id}
+                    sx={{
+                      "& .MuiTableCell-root": {
+                        color: (theme) => theme.palette.text.secondary,
+                        textAlign: "center",
+                      },
+                    }}
+                  >
+                    <TableCell>{peer.id}</TableCell>
+                    <TableCell>{peer.address ?? "unknown"}</TableCell>
+                    <TableCell sx={{ "&&": { color: peerStateColor[peer.state] } }}>{peer.state}</TableCell>
+                    <TableCell title={peer.public_key ?? undefined}>
+                      {peer.public_key ? `${peer.public_key.slice(0, 15)}...` : "-"}
+                    </TableCell>
+                    <TableCell>{peer.rtt === null ? "-" : `${peer.rtt} ms`}</TableCell>
+                    <TableCell>{peer.last_seen === null ? "-" : formatDateTime(Math.floor(peer.last_seen / 1000))}</TableCell>
+                  </TableRow>
+                ))}
+              </TableBody>
+            </Table>
+          </TableContainer>
+        </Grid>
       </Grid>
     </ThemeProvider>
   )
@@ -271,14 +405,18 @@ function App() {
 
 function CacheRow({
   data,
+  deleted = false,
   getHistory,
+  deleteData,
   history,
   showHistory,
   setShowHistory,
 }: {
   data: Cache
+  deleted?: boolean
   history: Transaction[]
   getHistory: (dataName: string) => void
+  deleteData: (dataName: string) => void
   showHistory: string
   setShowHistory: (showHistory: string) => void
 }) {
@@ -299,12 +437,14 @@ function CacheRow({
           },
         }}
       >
-        <TableCell align="center">{data.name}</TableCell>
-        <TableCell align="center">{data.size}</TableCell>
+        <TableCell align="center" sx={deleted ? { textDecoration: "line-through" } : undefined}>
+          {data.name}
+        </TableCell>
+        <TableCell align="center">{deleted ? "deleted" : data.size}</TableCell>
         <TableCell align="center">{formatDateTime(data.last_updated)}</TableCell>
         <TableCell align="center">{formatDateTime(data.last_accessed)}</TableCell>
         <TableCell>
-          {data.transactions}
+          {deleted ? "-" : data.transactions}
           <IconButton
             aria-label="expand row"
             size="small"
@@ -317,6 +457,11 @@ function CacheRow({
           >
             {showHistory === data.name ? <KeyboardArrowUp /> : <KeyboardArrowDown />}
           </IconButton>
+          {!deleted && (
+            <IconButton aria-label="delete" size="small" title={`Delete ${data.name}`} onClick={() => deleteData(data.name)}>
+              <DeleteOutline />
+            </IconButton>
+          )}
         </TableCell>
       </TableRow>
       <TableRow
@@ -346,23 +491,36 @@ function CacheRow({
                   <TableCell>Client ID</TableCell>
                   <TableCell>Data Name</TableCell>
                   <TableCell>Operation</TableCell>
+                  <TableCell>Value Hash</TableCell>
                   <TableCell>Timestamp</TableCell>
                 </TableRow>
               </TableHead>
               <TableBody>
-                {history.slice(page * rowsPerPage, page * rowsPerPage + rowsPerPage).map((transaction, i) => (
-                  <TableRow
-                    key={i}
-                    sx={{
-                      "& .MuiTableCell-root": { color: (theme) => theme.palette.text.secondary, textAlign: "center" },
-                    }}
-                  >
-                    <TableCell>{transaction.client_id}</TableCell>
-                    <TableCell>{transaction.data_name}</TableCell>
-                    <TableCell>{transaction.operation}</TableCell>
-                    <TableCell>{formatDateTime(transaction.timestamp)}</TableCell>
-                  </TableRow>
-                ))}
+                {history.slice(page * rowsPerPage, page * rowsPerPage + rowsPerPage).map((transaction, i) => {
+                  const tombstone = transaction.operation === "Delete" && !transaction.denied
+                  return (
+                    <TableRow
+                      key={i}
+                      sx={{
+                        "& .MuiTableCell-root": {
+                          color: (theme) => theme.palette.text.secondary,
+                          textAlign: "center",
+                          fontStyle: tombstone ? "italic" : undefined,
+                        },
+                      }}
+                    >
+                      <TableCell title={transaction.client_signature.public_key}>{transaction.client_id}</TableCell>
+                      <TableCell>{transaction.data_name}</TableCell>
+                      <TableCell>{transaction.denied ? `${transaction.operation} (denied)` : transaction.operation}</TableCell>
+                      {tombstone ? (
+                        <TableCell title={`Previous value: ${transaction.prev_value_hash}`}>tombstone</TableCell>
+                      ) : (
+                        <TableCell title={transaction.value_hash}>{transaction.value_hash.slice(0, 15)}...</TableCell>
+                      )}
+                      <TableCell>{formatDateTime(transaction.timestamp)}</TableCell>
+                    </TableRow>
+                  )
+                })}
               </TableBody>
               <TableFooter>
                 <TableRow>
@@ -386,6 +544,13 @@ function CacheRow({
 
 export default App
 
+const peerStateColor: Record<Peer["state"], string | undefined> = {
+  self: undefined,
+  alive: "#66bb6a",
+  suspect: "#ffa726",
+  dead: "#ef5350",
+}
+
 const formatDateTime = (timestamp: number): string => {
   if (timestamp === 0) return "N/A"
   return new Date(timestamp * 1000)